@@ -51,11 +51,22 @@ class Result:
     score: int
     duration: float
 
-    def __init__(self, result_json: dict, input_file: str, solver_version: str):
+    def __init__(
+        self,
+        result_json: dict,
+        input_file: str,
+        solver_version: str,
+        accuracy_json: Optional[dict] = None,
+    ):
         self.input_file = input_file
         self.solver_version = solver_version
         self.score = result_json["score"]
         self.duration = result_json["duration"]
+        # localビルドの推定器の最後の精度（例: mcmc_spearman, order_scaled_rmse）
+        if accuracy_json is not None:
+            for name, accuracy in accuracy_json["last"].items():
+                for key, value in accuracy.items():
+                    setattr(self, f"{name}_{key}", value)
 
 
 @dataclass
@@ -77,7 +88,8 @@ def run_case(
     input_file: str, output_file: str, solver_version: str, solver_cmd: str
 ) -> Result:
     while True:
-        cmd = f"{solver_cmd} < {input_file} > {output_file}"
+        # localビルドは、真の重さを読んで推定器の精度を出力する
+        cmd = f"AHC025_INPUT={input_file} {solver_cmd} < {input_file} > {output_file}"
         proc = subprocess.run(
             cmd, shell=True, stderr=subprocess.PIPE, timeout=TIME_LIMIT
         )
@@ -92,10 +104,22 @@ def run_case(
                 f"failed to parse result_str: {result_str}, input_file: {input_file}"
             )
 
-    result = Result(result_json, input_file, solver_version)
+    result = Result(
+        result_json, input_file, solver_version, parse_estimator_accuracy(stderr)
+    )
     return result
 
 
+def parse_estimator_accuracy(stderr: str) -> Optional[dict]:
+    for line in stderr.splitlines():
+        if line.startswith("estimator_accuracy:"):
+            try:
+                return json.loads(line[len("estimator_accuracy:") :])
+            except json.JSONDecodeError as e:
+                logger.error(e)
+    return None
+
+
 def run(
     data_dir: str,
     solver_path: str,
@@ -104,7 +128,7 @@ def run(
     database_csv: str,
     args: str = "",
 ) -> pd.DataFrame:
-    tester_path = "./tools/target/release/tester"
+    tester_path = "./target/release/tester"
     solver_cmd = f"{tester_path} {solver_path} {args}"
 
     start_seed = 0
@@ -9,7 +9,7 @@ if __name__ == "__main__":
 
     subprocess.run("cargo build --features local --release", shell=True)
     subprocess.run(
-        "./tools/target/release/tester ./target/release/ahc025"
+        "./target/release/tester ./target/release/ahc025"
         + f"< tools/in/{file}.txt > tools/out/{file}.txt",
         shell=True,
     )
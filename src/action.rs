@@ -1,149 +1,190 @@
 use crate::def::*;
+use crate::estimator::*;
+use crate::group_state::*;
+use crate::inference::*;
 use crate::interactor::*;
+use crate::merge_insertion::*;
+use crate::rank::*;
 use crate::util::*;
 
-fn select_lighter_item(group: &Vec<usize>, balancer: &mut Balancer) -> usize {
+///
+/// グループからランダムにアイテムを選び、それ以下の重さであることがわかっている
+/// グループ内のアイテムのうち、一番軽いものの候補からランダムに選ぶ
+///
+fn select_lighter_item(group: &[usize], balancer: &mut Balancer) -> usize {
     let item_idx_in_group = rnd::gen_range(0, group.len());
     let item_idx = group[item_idx_in_group];
-    let item_idx = balancer.find_lighter_in_group(item_idx, &group);
-    item_idx
+    let group_hash = balancer.to_hash(group);
+    let lighter_items = (balancer.item_order.lighter(item_idx) | 1 << item_idx) & group_hash;
+    select_random_item(balancer.item_order.lightest_candidates(lighter_items))
+}
+
+fn select_random_item(hash: u128) -> usize {
+    let i = rnd::gen_range(0, hash.count_ones() as usize);
+    iter_bits(hash).nth(i).unwrap()
 }
 
 pub fn action_move(
     heavier_g_idx: usize,
     lighter_g_idx: usize,
-    groups: &mut Vec<Vec<usize>>,
-    rank: &mut Vec<usize>,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
     input: &Input,
     balancer: &mut Balancer,
     interactor: &mut Interactor,
-) -> bool {
+) -> Result<bool, QueryError> {
     let item_idx = select_lighter_item(&groups[rank[heavier_g_idx]], balancer);
-    let i = groups[rank[heavier_g_idx]]
-        .iter()
-        .position(|x| *x == item_idx)
-        .unwrap();
-    groups[rank[heavier_g_idx]].swap_remove(i);
+    let (from_group, to_group) = (
+        groups[rank[heavier_g_idx]].clone(),
+        groups[rank[lighter_g_idx]].clone(),
+    );
+    if balancer.is_tabu(&[item_idx], &from_group, &to_group) {
+        return Ok(false);
+    }
+    groups.begin();
+    groups.remove(item_idx);
 
     // 集合の重さの差が改善しなければ不採用
-    match balancer.get_result(
+    let result = balancer.get_result(
         &groups[rank[lighter_g_idx]],
         &groups[rank[heavier_g_idx]],
         interactor,
-    ) {
+    );
+    match groups.rollback_on_err(result)? {
         BalanceResult::Right | BalanceResult::Equal => {
-            groups[rank[heavier_g_idx]].push(item_idx);
-            return false;
+            groups.rollback();
+            balancer.add_tabu(&[item_idx], &from_group, &to_group);
+            return Ok(false);
         }
         _ => {}
     }
 
     let prev_g_idx = rank[heavier_g_idx];
-    if !update_rank(
-        rank,
-        &groups,
+    let updated = rank.update(
+        groups,
         true,
         lighter_g_idx,
         heavier_g_idx,
         input,
         interactor,
         balancer,
-    ) {
+    );
+    if !matches!(updated, Ok(true)) {
         // 計測できなかった場合はとりあえず元に戻す
-        groups[prev_g_idx].push(item_idx);
+        groups.rollback();
+        rank.invalidate(prev_g_idx);
+        updated?;
         // rankは更新できなかったが、moveは成功している
-        return true;
+        return Ok(true);
     }
-    groups[rank[lighter_g_idx]].push(item_idx);
-    if !update_rank(
-        rank,
-        &groups,
+    let to_idx = rank[lighter_g_idx];
+    groups.insert(item_idx, to_idx);
+    groups.commit();
+    balancer.add_reverse_tabu(&[item_idx], &groups[prev_g_idx], &groups[to_idx]);
+    if !rank.update(
+        groups,
         false,
         lighter_g_idx,
         heavier_g_idx,
         input,
         interactor,
         balancer,
-    ) {
+    )? {
         // rankは更新できなかったが、moveは成功している
-        return true;
+        return Ok(true);
     }
 
-    true
+    Ok(true)
 }
 
 pub fn action_swap(
     heavier_g_idx: usize,
     lighter_g_idx: usize,
-    groups: &mut Vec<Vec<usize>>,
-    rank: &mut Vec<usize>,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
     input: &Input,
     balancer: &mut Balancer,
     interactor: &mut Interactor,
-) -> bool {
+) -> Result<bool, QueryError> {
     let item_idx_a = select_lighter_item(&groups[rank[lighter_g_idx]], balancer);
     let item_idx_b = select_lighter_item(&groups[rank[heavier_g_idx]], balancer);
+    let items = [item_idx_a, item_idx_b];
+    let (lighter_group, heavier_group) = (
+        groups[rank[lighter_g_idx]].clone(),
+        groups[rank[heavier_g_idx]].clone(),
+    );
+    if balancer.is_tabu(&items, &lighter_group, &heavier_group) {
+        return Ok(false);
+    }
 
     // 入れ替えようとしているアイテムの大小関係が集合の大小関係と一致しなければ不採用
-    match balancer.get_result(&vec![item_idx_a], &vec![item_idx_b], interactor) {
+    match balancer.get_result(&[item_idx_a], &[item_idx_b], interactor)? {
         BalanceResult::Left | BalanceResult::Equal => {}
-        _ => return false,
+        BalanceResult::Right => {
+            balancer.add_tabu(&items, &lighter_group, &heavier_group);
+            return Ok(false);
+        }
+        BalanceResult::Unknown => return Ok(false),
     }
 
-    let i = groups[rank[lighter_g_idx]]
-        .iter()
-        .position(|x| *x == item_idx_a)
-        .unwrap();
-    groups[rank[lighter_g_idx]].swap_remove(i);
-    let i = groups[rank[heavier_g_idx]]
-        .iter()
-        .position(|x| *x == item_idx_b)
-        .unwrap();
-    groups[rank[heavier_g_idx]].swap_remove(i);
-    match balancer.get_result(
+    groups.begin();
+    groups.remove(item_idx_a);
+    groups.remove(item_idx_b);
+    let result = balancer.get_result(
         &groups[rank[lighter_g_idx]],
         &groups[rank[heavier_g_idx]],
         interactor,
-    ) {
+    );
+    match groups.rollback_on_err(result)? {
         // 集合の重さの差が悪化したら不採用
-        BalanceResult::Right | BalanceResult::Unknown => {
-            groups[rank[lighter_g_idx]].push(item_idx_a);
-            groups[rank[heavier_g_idx]].push(item_idx_b);
-            return false;
+        BalanceResult::Right => {
+            groups.rollback();
+            balancer.add_tabu(&items, &lighter_group, &heavier_group);
+            Ok(false)
+        }
+        BalanceResult::Unknown => {
+            groups.rollback();
+            Ok(false)
         }
         _ => {
-            let to_idx = rank[lighter_g_idx];
-            groups[rank[heavier_g_idx]].push(item_idx_a);
-            if !update_rank(
-                rank,
-                &groups,
+            let (from_idx, to_idx) = (rank[heavier_g_idx], rank[lighter_g_idx]);
+            groups.insert(item_idx_a, from_idx);
+            let updated = rank.update(
+                groups,
                 true,
                 lighter_g_idx,
                 heavier_g_idx,
                 input,
                 interactor,
                 balancer,
-            ) {
+            );
+            if !matches!(updated, Ok(true)) {
                 // 最後に更新できなかった場合、変更先に更新する
-                groups[to_idx].push(item_idx_b);
+                groups.insert(item_idx_b, to_idx);
+                groups.commit();
+                balancer.add_reverse_tabu(&items, &groups[to_idx], &groups[from_idx]);
+                rank.invalidate_above(to_idx);
+                updated?;
                 // rankは更新できなかったが、swapは成功している
-                return true;
+                return Ok(true);
             }
-            groups[rank[lighter_g_idx]].push(item_idx_b);
-            if !update_rank(
-                rank,
-                &groups,
+            let to_idx = rank[lighter_g_idx];
+            groups.insert(item_idx_b, to_idx);
+            groups.commit();
+            balancer.add_reverse_tabu(&items, &groups[to_idx], &groups[from_idx]);
+            if !rank.update(
+                groups,
                 false,
                 lighter_g_idx,
                 heavier_g_idx,
                 input,
                 interactor,
                 balancer,
-            ) {
+            )? {
                 // rankは更新できなかったが、swapは成功している
-                return true;
+                return Ok(true);
             }
-            return true;
+            Ok(true)
         }
     }
 }
@@ -161,12 +202,12 @@ pub fn action_swap(
 pub fn action_swap2(
     heavier_g_idx: usize,
     lighter_g_idx: usize,
-    groups: &mut Vec<Vec<usize>>,
-    rank: &mut Vec<usize>,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
     input: &Input,
     balancer: &mut Balancer,
     interactor: &mut Interactor,
-) -> bool {
+) -> Result<bool, QueryError> {
     const TRIAL_COUNT: usize = 3;
     let mut item_indices_a =
         vec![groups[rank[lighter_g_idx]][rnd::gen_range(0, groups[rank[lighter_g_idx]].len())]];
@@ -174,7 +215,7 @@ pub fn action_swap2(
         vec![groups[rank[heavier_g_idx]][rnd::gen_range(0, groups[rank[heavier_g_idx]].len())]];
 
     // 入れ替えようとしているアイテムの大小関係が集合の大小関係と一致しなければ不採用
-    match balancer.get_result(&item_indices_a, &item_indices_b, interactor) {
+    match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
         BalanceResult::Right => {
             // 重い方に大小関係が入れ替わるものがあれば足す
             for _ in 0..TRIAL_COUNT {
@@ -183,7 +224,7 @@ pub fn action_swap2(
                     continue;
                 }
                 item_indices_b.push(b2);
-                match balancer.get_result(&item_indices_a, &item_indices_b, interactor) {
+                match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
                     BalanceResult::Left | BalanceResult::Equal => break,
                     _ => {
                         item_indices_b.pop();
@@ -199,7 +240,7 @@ pub fn action_swap2(
                     continue;
                 }
                 item_indices_a.push(a2);
-                match balancer.get_result(&item_indices_a, &item_indices_b, interactor) {
+                match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
                     BalanceResult::Left | BalanceResult::Equal => continue,
                     _ => {
                         item_indices_a.pop();
@@ -208,84 +249,529 @@ pub fn action_swap2(
             }
         }
         BalanceResult::Equal => {}
-        BalanceResult::Unknown => return false,
+        BalanceResult::Unknown => return Ok(false),
     }
-    match balancer.get_result(&item_indices_a, &item_indices_b, interactor) {
+    match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
         BalanceResult::Left | BalanceResult::Equal => {}
-        _ => return false,
+        _ => return Ok(false),
+    }
+
+    let adopted = exchange_items(
+        heavier_g_idx,
+        lighter_g_idx,
+        &item_indices_a,
+        &item_indices_b,
+        groups,
+        rank,
+        input,
+        balancer,
+        interactor,
+    )?;
+    if adopted && (item_indices_a.len() > 1 || item_indices_b.len() > 1) {
+        eprintln!("swap2: {:?} {:?}", item_indices_a, item_indices_b);
     }
+    Ok(adopted)
+}
 
-    for item_idx_a in item_indices_a.iter() {
-        let i = groups[rank[lighter_g_idx]]
-            .iter()
-            .position(|e| e == item_idx_a)
-            .unwrap();
-        groups[rank[lighter_g_idx]].remove(i);
+///
+/// 軽いグループのitem_indices_aと重いグループのitem_indices_bを入れ替える
+/// w(a) <= w(b)は確認済みであること
+///
+/// 取り除いた後の軽いグループが重いグループより重くなれば、差が悪化するので不採用
+///
+/// 問い合わせに失敗したらエラーを返す（入れ替えを確定する前なら元に戻す）
+///
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_items(
+    heavier_g_idx: usize,
+    lighter_g_idx: usize,
+    item_indices_a: &[usize],
+    item_indices_b: &[usize],
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    input: &Input,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+) -> Result<bool, QueryError> {
+    let items = [item_indices_a, item_indices_b].concat();
+    let (lighter_group, heavier_group) = (
+        groups[rank[lighter_g_idx]].clone(),
+        groups[rank[heavier_g_idx]].clone(),
+    );
+    if balancer.is_tabu(&items, &lighter_group, &heavier_group) {
+        return Ok(false);
     }
-    for item_idx_b in item_indices_b.iter() {
-        let i = groups[rank[heavier_g_idx]]
-            .iter()
-            .position(|e| e == item_idx_b)
-            .unwrap();
-        groups[rank[heavier_g_idx]].remove(i);
+    groups.begin();
+    for item_idx in items.iter() {
+        groups.remove(*item_idx);
     }
 
-    match balancer.get_result(
+    let result = balancer.get_result(
         &groups[rank[lighter_g_idx]],
         &groups[rank[heavier_g_idx]],
         interactor,
-    ) {
+    );
+    match groups.rollback_on_err(result)? {
         // 集合の重さの差が悪化したら不採用
-        BalanceResult::Right | BalanceResult::Unknown => {
-            for item_idx_a in item_indices_a.iter() {
-                groups[rank[lighter_g_idx]].push(*item_idx_a);
-            }
-            for item_idx_b in item_indices_b.iter() {
-                groups[rank[heavier_g_idx]].push(*item_idx_b);
-            }
-            return false;
+        BalanceResult::Right => {
+            groups.rollback();
+            balancer.add_tabu(&items, &lighter_group, &heavier_group);
+            Ok(false)
+        }
+        BalanceResult::Unknown => {
+            groups.rollback();
+            Ok(false)
         }
         _ => {
-            let to_idx = rank[lighter_g_idx];
+            let (from_idx, to_idx) = (rank[heavier_g_idx], rank[lighter_g_idx]);
             for item_idx_a in item_indices_a.iter() {
-                groups[rank[heavier_g_idx]].push(*item_idx_a);
+                groups.insert(*item_idx_a, from_idx);
             }
-            if !update_rank(
-                rank,
-                &groups,
+            let updated = rank.update(
+                groups,
                 true,
                 lighter_g_idx,
                 heavier_g_idx,
                 input,
                 interactor,
                 balancer,
-            ) {
+            );
+            if !matches!(updated, Ok(true)) {
                 for item_idx_b in item_indices_b.iter() {
-                    groups[to_idx].push(*item_idx_b);
+                    groups.insert(*item_idx_b, to_idx);
                 }
+                groups.commit();
+                balancer.add_reverse_tabu(&items, &groups[to_idx], &groups[from_idx]);
+                rank.invalidate_above(to_idx);
+                updated?;
                 // rankは更新できなかったが、swapは成功している
-                return true;
+                return Ok(true);
             }
+            let to_idx = rank[lighter_g_idx];
             for item_idx_b in item_indices_b.iter() {
-                groups[rank[lighter_g_idx]].push(*item_idx_b);
+                groups.insert(*item_idx_b, to_idx);
             }
-            if !update_rank(
-                rank,
-                &groups,
+            groups.commit();
+            balancer.add_reverse_tabu(&items, &groups[to_idx], &groups[from_idx]);
+            if !rank.update(
+                groups,
                 false,
                 lighter_g_idx,
                 heavier_g_idx,
                 input,
                 interactor,
                 balancer,
-            ) {
+            )? {
                 // rankは更新できなかったが、swapは成功している
-                return true;
+                return Ok(true);
+            }
+            Ok(true)
+        }
+    }
+}
+
+///
+/// 重いグループH、その間の順位のグループM、軽いグループLの3つで、アイテムを重い方から軽い方へ送る
+///
+/// - 連鎖: Hのxを Mへ、Mのyを Lへ移す
+/// - 巡回（cyclicがtrue）: さらにLのzをHへ移す
+///
+/// 巡回はMとLでyとzを交換してから、HとMでxとzを交換するのと同じなので、
+/// それぞれの段階で2グループの重さの差が縮まることを確認すれば、全体でも改善する
+///
+/// 1. （巡回のみ）w(z) <= w(y)、w(z) <= w(x)
+/// 2. w(M - y) <= w(H - x)
+/// 3. w(L - z) <= w(M - y)
+///
+/// 変更後は3つのグループ全ての順位を更新する
+///
+#[allow(clippy::too_many_arguments)]
+pub fn action_chain(
+    heavier_g_idx: usize,
+    lighter_g_idx: usize,
+    cyclic: bool,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    input: &Input,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+) -> Result<bool, QueryError> {
+    if heavier_g_idx < lighter_g_idx + 2 {
+        return Ok(false);
+    }
+    let middle_g_idx = rnd::gen_range(lighter_g_idx + 1, heavier_g_idx);
+    let (h, m, l) = (rank[heavier_g_idx], rank[middle_g_idx], rank[lighter_g_idx]);
+    let x = select_lighter_item(&groups[h], balancer);
+    let y = select_lighter_item(&groups[m], balancer);
+    let z = if cyclic {
+        let z = select_lighter_item(&groups[l], balancer);
+        for other in [y, x] {
+            match balancer.get_result(&[z], &[other], interactor)? {
+                BalanceResult::Left | BalanceResult::Equal => {}
+                _ => return Ok(false),
             }
-            if item_indices_a.len() > 1 || item_indices_b.len() > 1 {
-                eprintln!("swap2: {:?} {:?}", item_indices_a, item_indices_b);
+        }
+        Some(z)
+    } else {
+        None
+    };
+
+    groups.begin();
+    groups.remove(x);
+    groups.remove(y);
+    if let Some(z) = z {
+        groups.remove(z);
+    }
+    // 各段階で集合の重さの差が悪化したら不採用
+    // 連鎖はmoveを2回、巡回はswapを2回するのと同じなので、等しい場合の扱いもそれに合わせる
+    for (lighter, heavier) in [(m, h), (l, m)] {
+        let result = balancer.get_result(&groups[lighter], &groups[heavier], interactor);
+        match groups.rollback_on_err(result)? {
+            BalanceResult::Left => {}
+            BalanceResult::Equal if cyclic => {}
+            _ => {
+                groups.rollback();
+                return Ok(false);
             }
+        }
+    }
+    groups.insert(x, m);
+    groups.insert(y, l);
+    if let Some(z) = z {
+        groups.insert(z, h);
+    }
+    groups.commit();
+
+    // 3つとも周りとの大小関係はわからなくなるので、先に確認済みでなくしておく
+    for g_idx in [h, m, l] {
+        rank.invalidate(g_idx);
+    }
+    // Hは軽く、Lは重くなる。Mはxとyの大小関係で決まる
+    let m_from_up = match balancer.peek_result(&[x], &[y]) {
+        BalanceResult::Left => Some(true),
+        BalanceResult::Right | BalanceResult::Equal => Some(false),
+        BalanceResult::Unknown => None,
+    };
+    let d = groups.len();
+    for (g_idx, from_up) in [(h, Some(true)), (l, Some(false)), (m, m_from_up)] {
+        let Some(from_up) = from_up else {
+            continue;
+        };
+        let pos = rank.position(g_idx);
+        let (lower, upper) = if from_up { (0, pos) } else { (pos, d - 1) };
+        if !rank.update(groups, from_up, lower, upper, input, interactor, balancer)? {
+            // rankは更新できなかったが、chainは成功している
+            return Ok(true);
+        }
+    }
+    Ok(true)
+}
+
+/// 焼きなましで、推定値を更新する時のサンプリングの周回数
+const ANNEAL_SWEEP_COUNT: usize = 2; // :param
+
+///
+/// 焼きなまし用のaction。推定した重さでスコアの変化を見積もり、悪化する変更も温度に応じて採用する
+///
+/// 1. ランダムな2グループA, Bを選び、Aのアイテムxを移すか、Bのアイテムyと交換する候補を作る
+/// 2. 推定した重さで分散の変化量を求め、悪化するならexp(-悪化量 / temperature)の確率でのみ採用する
+/// 3. 変更後のAとBを実際に比較し、推定した大小関係と逆なら推定が誤っているので元に戻す
+///    比較結果は制約として次の推定値の更新で使われる
+///
+/// 順位は変わりうるので、AとBの周りの大小関係は確認済みではなくなる（repairで直す）
+///
+#[allow(clippy::too_many_arguments)]
+pub fn action_anneal(
+    temperature: f64,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+    estimator: &mut McmcEstimator,
+) -> Result<bool, QueryError> {
+    estimator.update(balancer, ANNEAL_SWEEP_COUNT);
+    let d = groups.len();
+    let g_a = rnd::gen_range(0, d);
+    let mut g_b = rnd::gen_range(0, d - 1);
+    if g_b >= g_a {
+        g_b += 1;
+    }
+    if groups[g_a].len() <= 1 {
+        return Ok(false);
+    }
+    let item_a = groups[g_a][rnd::gen_range(0, groups[g_a].len())];
+    let item_b = if rnd::nextf() < 0.5 {
+        Some(groups[g_b][rnd::gen_range(0, groups[g_b].len())])
+    } else {
+        None
+    };
+
+    // AからBへtだけ移すと、分散（の定数倍）は2t(t - w(A) + w(B))だけ変わる
+    let (w_a, w_b) = (
+        estimator.group_weight(&groups[g_a]),
+        estimator.group_weight(&groups[g_b]),
+    );
+    let t = estimator.weight(item_a) - item_b.map_or(0., |e| estimator.weight(e));
+    let delta = 2. * t * (t - w_a + w_b);
+    if delta > 0. && rnd::nextf() >= (-delta / temperature).exp() {
+        return Ok(false);
+    }
+
+    groups.begin();
+    groups.remove(item_a);
+    groups.insert(item_a, g_b);
+    if let Some(item_b) = item_b {
+        groups.remove(item_b);
+        groups.insert(item_b, g_a);
+    }
+
+    let is_a_lighter = w_a - t <= w_b + t;
+    let result = balancer.get_result(&groups[g_a], &groups[g_b], interactor);
+    match groups.rollback_on_err(result)? {
+        BalanceResult::Equal => {}
+        BalanceResult::Left if is_a_lighter => {}
+        BalanceResult::Right if !is_a_lighter => {}
+        _ => {
+            groups.rollback();
+            return Ok(false);
+        }
+    }
+    groups.commit();
+    rank.invalidate(g_a);
+    rank.invalidate(g_b);
+    Ok(true)
+}
+
+/// 混ぜて分け直す2グループのアイテム数の合計の上限（ソートのクエリが多くなりすぎないように）
+const RESPLIT_MAX_ITEM_COUNT: usize = 24; // :param
+
+///
+/// 軽いグループLと重いグループHのアイテムを混ぜてソートし、2つに分け直す
+///
+/// 1. L ∪ Hのアイテムを比較でソートする
+/// 2. 重い方から2個ずつ、重い方を今軽い側A、軽い方をもう一方の側Bに入れる（比較で軽い側を確認する）
+/// 3. Lの位置にA、Hの位置にBを置くと考えると、LとHの間でa = L ∩ Bとb = H ∩ Aを交換することになる
+///    w(a) < w(b)とw(L - a) <= w(H - b)を確認できれば、差が縮まるので採用する
+///
+#[allow(clippy::too_many_arguments)]
+pub fn action_resplit(
+    heavier_g_idx: usize,
+    lighter_g_idx: usize,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    input: &Input,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+) -> Result<bool, QueryError> {
+    let (l, h) = (rank[lighter_g_idx], rank[heavier_g_idx]);
+    let items: Vec<usize> = groups[l].iter().chain(groups[h].iter()).copied().collect();
+    if items.len() > RESPLIT_MAX_ITEM_COUNT {
+        return Ok(false);
+    }
+
+    // 比較関数からはエラーを返せないので、比較できなかったら覚えておき、以降は問い合わせない
+    let mut is_unknown = false;
+    let mut error = None;
+    let sorted_items = merge_insertion_sort(items, &mut |a, b| {
+        if is_unknown || error.is_some() {
             return true;
         }
+        match balancer.get_result(&[a], &[b], interactor) {
+            Ok(BalanceResult::Left | BalanceResult::Equal) => true,
+            Ok(BalanceResult::Right) => false,
+            Ok(BalanceResult::Unknown) => {
+                is_unknown = true;
+                true
+            }
+            Err(e) => {
+                error = Some(e);
+                true
+            }
+        }
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    if is_unknown {
+        return Ok(false);
+    }
+
+    // 重い順に2個ずつ分ける。a_is_lighter: 今Aの方が軽い（空の時はどちらでもよい）
+    let (mut side_a, mut side_b) = (vec![], vec![]);
+    let mut a_is_lighter = true;
+    for pair in sorted_items.rchunks(2).map(|pair| pair.iter().rev()) {
+        let mut pair = pair.copied();
+        let (heavier_item, lighter_item) = (pair.next().unwrap(), pair.next());
+        let (to_lighter, to_heavier) = if a_is_lighter {
+            (&mut side_a, &mut side_b)
+        } else {
+            (&mut side_b, &mut side_a)
+        };
+        to_lighter.push(heavier_item);
+        if let Some(lighter_item) = lighter_item {
+            to_heavier.push(lighter_item);
+        }
+        if side_a.is_empty() || side_b.is_empty() {
+            a_is_lighter = side_a.is_empty();
+            continue;
+        }
+        a_is_lighter = match balancer.get_result(&side_a, &side_b, interactor)? {
+            BalanceResult::Left | BalanceResult::Equal => true,
+            BalanceResult::Right => false,
+            BalanceResult::Unknown => return Ok(false),
+        };
+    }
+    if !a_is_lighter {
+        std::mem::swap(&mut side_a, &mut side_b);
+    }
+
+    // Lから出ていくアイテムと、Hから出ていくアイテム
+    let item_indices_a: Vec<usize> = groups[l]
+        .iter()
+        .filter(|e| side_b.contains(e))
+        .copied()
+        .collect();
+    let item_indices_b: Vec<usize> = groups[h]
+        .iter()
+        .filter(|e| side_a.contains(e))
+        .copied()
+        .collect();
+    if item_indices_b.is_empty() {
+        return Ok(false);
+    }
+    if !item_indices_a.is_empty() {
+        match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
+            BalanceResult::Left => {}
+            _ => return Ok(false),
+        }
+    }
+
+    exchange_items(
+        heavier_g_idx,
+        lighter_g_idx,
+        &item_indices_a,
+        &item_indices_b,
+        groups,
+        rank,
+        input,
+        balancer,
+        interactor,
+    )
+}
+
+/// 推定した重さの差が、グループの重さの差の半分に最も近くなる組を探す時の、各グループから選ぶ個数の上限
+const EXCHANGE_MAX_ITEM_COUNT: usize = 2; // :param
+/// 交換後のスコアが良くなるサンプルの割合がこれ未満なら、問い合わせずに不採用にする
+const EXCHANGE_MIN_IMPROVEMENT_PROBABILITY: f64 = 0.5; // :param
+
+///
+/// 推定した重さを使って、軽いグループのk個以下のアイテムAと重いグループのm個以下のアイテムBを交換する
+/// 推定値は呼び出し側で更新しておく
+///
+/// 1. 推定した重さで、w(B) - w(A)がグループの重さの差の半分に最も近くなる組を探す
+/// 2. 推定値のサンプルがあれば、交換後のスコアが良くなるサンプルが少ない場合は問い合わせずに不採用にする
+/// 3. 交換後の2グループの重さの差が縮まることを、以下の2回の比較で確認する
+///     1. w(A) <= w(B)
+///     2. w(軽いグループ - A) <= w(重いグループ - B)
+///
+#[allow(clippy::too_many_arguments)]
+pub fn action_exchange(
+    heavier_g_idx: usize,
+    lighter_g_idx: usize,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    input: &Input,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+    estimator: &dyn WeightEstimator,
+) -> Result<bool, QueryError> {
+    let lighter_group = &groups[rank[lighter_g_idx]];
+    let heavier_group = &groups[rank[heavier_g_idx]];
+    let gap = estimator.group_weight(heavier_group) - estimator.group_weight(lighter_group);
+    if gap <= 0. {
+        return Ok(false);
+    }
+
+    let subsets_a = enumerate_subsets(lighter_group, EXCHANGE_MAX_ITEM_COUNT, estimator);
+    let mut subsets_b = enumerate_subsets(heavier_group, EXCHANGE_MAX_ITEM_COUNT, estimator);
+    subsets_b.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // w(B) - w(A)がgap / 2に近いものを二分探索で探す
+    let mut best: Option<(f64, usize, usize)> = None;
+    for (a_idx, (weight_a, _)) in subsets_a.iter().enumerate() {
+        let target = weight_a + gap / 2.;
+        let pos = subsets_b.partition_point(|(weight_b, _)| *weight_b < target);
+        for b_idx in [pos.wrapping_sub(1), pos] {
+            let Some((weight_b, items_b)) = subsets_b.get(b_idx) else {
+                continue;
+            };
+            let diff = weight_b - weight_a;
+            if items_b.is_empty() || diff <= 0. || diff >= gap {
+                continue;
+            }
+            let error = (diff - gap / 2.).abs();
+            if best.is_some_and(|(best_error, _, _)| best_error <= error) {
+                continue;
+            }
+            best = Some((error, a_idx, b_idx));
+        }
+    }
+    let Some((_, a_idx, b_idx)) = best else {
+        return Ok(false);
+    };
+    let item_indices_a = subsets_a[a_idx].1.clone();
+    let item_indices_b = subsets_b[b_idx].1.clone();
+
+    let mut proposal = groups.to_vec();
+    let (lighter, heavier) = (rank[lighter_g_idx], rank[heavier_g_idx]);
+    proposal[lighter].retain(|e| !item_indices_a.contains(e));
+    proposal[heavier].retain(|e| !item_indices_b.contains(e));
+    proposal[lighter].extend(item_indices_b.iter());
+    proposal[heavier].extend(item_indices_a.iter());
+    if estimator
+        .improvement_probability(groups, &proposal)
+        .is_some_and(|p| p < EXCHANGE_MIN_IMPROVEMENT_PROBABILITY)
+    {
+        return Ok(false);
+    }
+
+    match balancer.get_result(&item_indices_a, &item_indices_b, interactor)? {
+        BalanceResult::Left | BalanceResult::Equal => {}
+        _ => return Ok(false),
+    }
+    exchange_items(
+        heavier_g_idx,
+        lighter_g_idx,
+        &item_indices_a,
+        &item_indices_b,
+        groups,
+        rank,
+        input,
+        balancer,
+        interactor,
+    )
+}
+
+/// 空集合を含む、max_size個以下のアイテムの組と、その推定した重さを列挙する
+fn enumerate_subsets(
+    group: &[usize],
+    max_size: usize,
+    estimator: &dyn WeightEstimator,
+) -> Vec<(f64, Vec<usize>)> {
+    let mut subsets = vec![(0., vec![])];
+    let mut frontier = vec![(0., vec![], 0)]; // (重さ, アイテム, 次に追加できるgroup内の位置)
+    for _ in 0..max_size {
+        let mut next_frontier = vec![];
+        for (weight, items, start) in frontier {
+            for (i, item_idx) in group.iter().enumerate().skip(start) {
+                let mut next_items: Vec<usize> = items.clone();
+                next_items.push(*item_idx);
+                let next_weight = weight + estimator.weight(*item_idx);
+                subsets.push((next_weight, next_items.clone()));
+                next_frontier.push((next_weight, next_items, i + 1));
+            }
+        }
+        frontier = next_frontier;
     }
+    subsets
 }
@@ -0,0 +1,227 @@
+//!
+//! 入力ファイルの生成（run.pyが読むtools/in/NNNN.txtと同じ形式）
+//!
+//! 使い方: gen [--dir DIR] [--n MIN MAX] [--d MIN MAX] [--q MIN MAX] <seeds.txt>
+//!
+//! seeds.txtの各行のseedから、i行目の入力をDIR/{i:04}.txtに書く（DIRの既定値はtools/in）
+//! 1行目にN D Q、2行目に重さを書く（重さはtesterだけが読み、solverには渡さない）
+//!
+//! 分布はコンテストと同じ
+//! - N: [30, 100]の一様乱数
+//! - D: [2, floor(N/4)]の一様乱数
+//! - Q: round(N * 2^x)、xは[1, 5]の一様乱数
+//! - 重さ: λ = 1e-5の指数分布を四捨五入して1以上にしたもの。1e5 * N / Dを超えたら引き直す
+//!
+//! --n, --d, --qで範囲を狭められる（コンテストの制約との共通部分を使う）
+//! --qを指定した時も、Qはround(N * 2^x)のまま、xをQが範囲に入る部分の一様乱数にする
+//! （Qの範囲内で一様ではなく、小さいQほど出やすい）
+//!
+use std::io::Write;
+
+const N_RANGE: (usize, usize) = (30, 100);
+/// Q = round(N * 2^x)のxの範囲
+const Q_EXPONENT_RANGE: (f64, f64) = (1., 5.);
+const LAMBDA: f64 = 1e-5;
+
+/// SplitMix64（seedごとに同じ入力を作れればよいので、コンテストのgeneratorの乱数とは一致しない）
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1)の一様乱数
+    fn nextf(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// [min, max]の一様乱数
+    fn range(&mut self, min: usize, max: usize) -> usize {
+        min + (self.next_u64() % (max - min + 1) as u64) as usize
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    dir: String,
+    n: Option<(usize, usize)>,
+    d: Option<(usize, usize)>,
+    q: Option<(usize, usize)>,
+    seeds_path: String,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let usage = "usage: gen [--dir DIR] [--n MIN MAX] [--d MIN MAX] [--q MIN MAX] <seeds.txt>\n\
+                 (with --q, Q is still round(N * 2^x); x is restricted so that Q is in the range)";
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = Options {
+        dir: "tools/in".to_owned(),
+        ..Default::default()
+    };
+    let mut seeds_path = None;
+    let mut i = 0;
+    while i < args.len() {
+        let range = |i: usize| -> Result<(usize, usize), String> {
+            let parse = |j: usize| -> Result<usize, String> {
+                let v = args.get(j).ok_or(usage)?;
+                v.parse().map_err(|_| format!("invalid number: {}", v))
+            };
+            let (min, max) = (parse(i + 1)?, parse(i + 2)?);
+            if min > max {
+                return Err(format!("invalid range for {}: {} > {}", args[i], min, max));
+            }
+            Ok((min, max))
+        };
+        match args[i].as_str() {
+            "--dir" => {
+                options.dir = args.get(i + 1).ok_or(usage)?.clone();
+                i += 2;
+            }
+            "--n" => {
+                options.n = Some(range(i)?);
+                i += 3;
+            }
+            "--d" => {
+                options.d = Some(range(i)?);
+                i += 3;
+            }
+            "--q" => {
+                options.q = Some(range(i)?);
+                i += 3;
+            }
+            path if seeds_path.is_none() && !path.starts_with("--") => {
+                seeds_path = Some(path.to_owned());
+                i += 1;
+            }
+            _ => return Err(usage.to_owned()),
+        }
+    }
+    options.seeds_path = seeds_path.ok_or(usage)?;
+    Ok(options)
+}
+
+/// 2つの範囲の共通部分（空ならエラー）
+fn intersect(
+    name: &str,
+    base: (usize, usize),
+    limit: Option<(usize, usize)>,
+) -> Result<(usize, usize), String> {
+    let Some(limit) = limit else {
+        return Ok(base);
+    };
+    let (min, max) = (base.0.max(limit.0), base.1.min(limit.1));
+    if min > max {
+        return Err(format!(
+            "no valid {} in {:?} (constraint {:?})",
+            name, limit, base
+        ));
+    }
+    Ok((min, max))
+}
+
+///
+/// Q = round(N * 2^x)を作る。xは一様乱数
+/// 範囲の指定があれば、丸めた後のQがその範囲に入るxだけに絞る（指定がなければxの範囲はそのまま）
+///
+fn generate_q(rng: &mut Rng, n: usize, limit: Option<(usize, usize)>) -> Result<usize, String> {
+    let (x_min, x_max) = Q_EXPONENT_RANGE;
+    let (q_min, q_max) = intersect(
+        "Q",
+        (
+            (n as f64 * 2f64.powf(x_min)).round() as usize,
+            (n as f64 * 2f64.powf(x_max)).round() as usize,
+        ),
+        limit,
+    )?;
+    // round(N * 2^x)がq_min以上q_max以下になるのは、N * 2^xが[q_min - 0.5, q_max + 0.5)にある時
+    let x_min = ((q_min as f64 - 0.5) / n as f64).log2().max(x_min);
+    let x_max = ((q_max as f64 + 0.5) / n as f64).log2().min(x_max);
+    let x = x_min + (x_max - x_min) * rng.nextf();
+    // 境界での浮動小数点の誤差で範囲を外れないようにする
+    Ok(((n as f64 * 2f64.powf(x)).round() as usize).clamp(q_min, q_max))
+}
+
+/// seedから(N, D, Q, 重さ)を作る
+fn generate(seed: u64, options: &Options) -> Result<(usize, usize, usize, Vec<i64>), String> {
+    let mut rng = Rng(seed);
+    let (n_min, n_max) = intersect("N", N_RANGE, options.n)?;
+    let n = rng.range(n_min, n_max);
+    let (d_min, d_max) = intersect("D", (2, n / 4), options.d)?;
+    let d = rng.range(d_min, d_max);
+    let q = generate_q(&mut rng, n, options.q)?;
+    let max_weight = 1e5 * n as f64 / d as f64;
+    let weights = (0..n)
+        .map(|_| loop {
+            let w = (-(1. - rng.nextf()).ln() / LAMBDA).round().max(1.);
+            if w <= max_weight {
+                break w as i64;
+            }
+        })
+        .collect();
+    Ok((n, d, q, weights))
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let seeds_text = std::fs::read_to_string(&options.seeds_path)
+        .map_err(|e| format!("failed to read {}: {}", options.seeds_path, e))?;
+    std::fs::create_dir_all(&options.dir)
+        .map_err(|e| format!("failed to create {}: {}", options.dir, e))?;
+    for (idx, line) in seeds_text
+        .lines()
+        .filter(|e| !e.trim().is_empty())
+        .enumerate()
+    {
+        let seed: u64 = line
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid seed: {}", line))?;
+        let (n, d, q, weights) =
+            generate(seed, options).map_err(|e| format!("seed {}: {}", seed, e))?;
+        let path = format!("{}/{:04}.txt", options.dir, idx);
+        let mut file = std::fs::File::create(&path)
+            .map_err(|e| format!("failed to create {}: {}", path, e))?;
+        let weights: Vec<String> = weights.iter().map(|e| e.to_string()).collect();
+        writeln!(file, "{} {} {}\n{}", n, d, q, weights.join(" "))
+            .map_err(|e| format!("failed to write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = parse_args().and_then(|options| run(&options)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_q_keeps_the_exponential_law_within_the_range() {
+        let n = 50;
+        let mut rng = Rng(1);
+        // 範囲を指定しない時は、これまでと同じ式と同じ乱数の使い方
+        let mut expected_rng = Rng(1);
+        for _ in 0..1000 {
+            let expected = (n as f64 * 2f64.powf(1. + 4. * expected_rng.nextf())).round() as usize;
+            assert_eq!(generate_q(&mut rng, n, None), Ok(expected));
+        }
+        // 範囲内に入り、xが一様なので範囲の下半分の方が多く出る
+        let (q_min, q_max) = (150, 900);
+        let qs: Vec<usize> = (0..10000)
+            .map(|_| generate_q(&mut rng, n, Some((q_min, q_max))).unwrap())
+            .collect();
+        assert!(qs.iter().all(|q| (q_min..=q_max).contains(q)));
+        let lower_count = qs.iter().filter(|q| **q < (q_min + q_max) / 2).count();
+        assert!(lower_count > qs.len() * 6 / 10);
+        assert_eq!(generate_q(&mut rng, n, Some((300, 300))), Ok(300));
+        assert!(generate_q(&mut rng, n, Some((1, 50))).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//!
+//! ローカルのテスタ（コンテストのtesterと同じ対話の手順）
+//!
+//! 使い方: tester [--time-limit 秒] <solver> [args...] < tools/in/0000.txt > tools/out/0000.txt
+//!
+//! 1. 入力ファイル（1行目にN D Q、2行目に重さ）を読み、solverにN D Qだけを渡す
+//! 2. solverの問い合わせに、重さを比べて < > = を返す（ちょうどQ回）
+//! 3. 最後の出力（グループ分け）からスコアを計算する
+//!
+//! solverの出力は全てそのまま標準出力に書く（ビジュアライザ用）
+//! 標準エラー出力の最後に、run.pyが読む`result: {"score": .., "duration": ..}`を書く
+//! 不正な出力・時間切れの場合はスコアを0とし、理由をerrorに入れる
+//!
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// コンテストの実行時間制限と同じ（起動や入出力の時間も含めて測る）
+const DEFAULT_TIME_LIMIT: f64 = 2.0;
+
+struct TesterInput {
+    n: usize,
+    d: usize,
+    q: usize,
+    weights: Vec<i64>,
+}
+
+fn read_input() -> Result<TesterInput, String> {
+    let mut text = String::new();
+    std::io::stdin()
+        .read_to_string(&mut text)
+        .map_err(|e| format!("failed to read input: {}", e))?;
+    let mut tokens = text.split_whitespace().map(|e| e.parse::<i64>());
+    let mut next = |name: &str| match tokens.next() {
+        Some(Ok(v)) => Ok(v),
+        _ => Err(format!("failed to parse {} in input", name)),
+    };
+    let (n, d, q) = (
+        next("N")? as usize,
+        next("D")? as usize,
+        next("Q")? as usize,
+    );
+    let weights = (0..n)
+        .map(|i| next(&format!("w[{}]", i)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(TesterInput { n, d, q, weights })
+}
+
+/// (--time-limitの値, solverのコマンドと引数)
+fn parse_args() -> Result<(f64, Vec<String>), String> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut time_limit = DEFAULT_TIME_LIMIT;
+    if args.first().map(|e| e.as_str()) == Some("--time-limit") {
+        if args.len() < 2 {
+            return Err("--time-limit needs a value".to_owned());
+        }
+        time_limit = args[1]
+            .parse()
+            .ok()
+            .filter(|e: &f64| *e > 0.)
+            .ok_or_else(|| format!("invalid time limit: {}", args[1]))?;
+        args.drain(..2);
+    }
+    if args.is_empty() {
+        return Err("usage: tester [--time-limit SEC] <solver> [args...] < input".to_owned());
+    }
+    Ok((time_limit, args))
+}
+
+/// solverの標準出力を、別のスレッドで1行ずつ読んで送る（時間切れを検出するため）
+fn spawn_line_reader(child: &mut Child) -> Receiver<String> {
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+struct Session {
+    receiver: Receiver<String>,
+    deadline: Instant,
+}
+
+impl Session {
+    /// コメント（#から始まる行）と空行を飛ばして、次の行を返す
+    fn next_line(&self) -> Result<String, String> {
+        loop {
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            let line = match self.receiver.recv_timeout(timeout) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => return Err("time limit exceeded".to_owned()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("solver closed its output".to_owned())
+                }
+            };
+            println!("{}", line);
+            if !line.trim().is_empty() && !line.starts_with('#') {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+fn parse_numbers(line: &str) -> Result<Vec<usize>, String> {
+    line.split_whitespace()
+        .map(|e| e.parse().map_err(|_| format!("invalid number {:?}", e)))
+        .collect()
+}
+
+/// 問い合わせの行を(左, 右)に分ける。両側が空でなく、範囲内で、重複がないこと
+fn parse_query(line: &str, n: usize) -> Result<(Vec<usize>, Vec<usize>), String> {
+    let v = parse_numbers(line)?;
+    if v.len() < 2 || v[0] == 0 || v[1] == 0 {
+        return Err(format!("invalid query {:?}", line));
+    }
+    // 個数が大きすぎると足し算があふれるので、あふれたら不正とする
+    let expected_len = v[0].checked_add(v[1]).and_then(|s| s.checked_add(2));
+    if expected_len != Some(v.len()) {
+        return Err(format!("invalid query {:?}", line));
+    }
+    let (left, right) = (v[2..2 + v[0]].to_vec(), v[2 + v[0]..].to_vec());
+    let mut used = vec![false; n];
+    for &e in left.iter().chain(right.iter()) {
+        if e >= n {
+            return Err(format!("item {} is out of range in query {:?}", e, line));
+        }
+        if used[e] {
+            return Err(format!("item {} is used twice in query {:?}", e, line));
+        }
+        used[e] = true;
+    }
+    Ok((left, right))
+}
+
+/// 最後の出力を、アイテムごとのグループ番号に分ける。N個あり、全て0..Dの範囲にあること
+fn parse_answer(line: &str, n: usize, d: usize) -> Result<Vec<usize>, String> {
+    let answer = parse_numbers(line)?;
+    if answer.len() != n {
+        return Err(format!(
+            "answer has {} numbers, expected {}",
+            answer.len(),
+            n
+        ));
+    }
+    if let Some(g) = answer.iter().find(|g| **g >= d) {
+        return Err(format!("group {} is out of range 0..{}", g, d));
+    }
+    Ok(answer)
+}
+
+fn write_line(stdin: &mut ChildStdin, line: &str) -> Result<(), String> {
+    writeln!(stdin, "{}", line)
+        .and_then(|_| stdin.flush())
+        .map_err(|e| format!("failed to write to solver: {}", e))
+}
+
+/// 1 + round(100 * グループの重さの標準偏差)
+fn compute_score(input: &TesterInput, answer: &[usize]) -> i64 {
+    let mut group_weights = vec![0.; input.d];
+    for (i, &g) in answer.iter().enumerate() {
+        group_weights[g] += input.weights[i] as f64;
+    }
+    let mean = group_weights.iter().sum::<f64>() / input.d as f64;
+    let variance = group_weights
+        .iter()
+        .map(|w| (w - mean) * (w - mean))
+        .sum::<f64>()
+        / input.d as f64;
+    1 + (100. * variance.sqrt()).round() as i64
+}
+
+fn run(input: &TesterInput, command: &[String], time_limit: f64) -> Result<i64, String> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to spawn {}: {}", command[0], e))?;
+    let session = Session {
+        receiver: spawn_line_reader(&mut child),
+        deadline: Instant::now() + Duration::from_secs_f64(time_limit),
+    };
+    let result = interact(input, &mut child, &session);
+    // resultの行が標準エラー出力の最後になるように、solverが終わるのを待つ（時間切れなら終わらせる）
+    if result.is_ok() {
+        while Instant::now() < session.deadline {
+            if let Ok(Some(_)) = child.try_wait() {
+                return result;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
+
+fn interact(input: &TesterInput, child: &mut Child, session: &Session) -> Result<i64, String> {
+    let mut stdin = child.stdin.take().unwrap();
+    write_line(&mut stdin, &format!("{} {} {}", input.n, input.d, input.q))?;
+    for query_idx in 0..input.q {
+        let line = session.next_line()?;
+        let (left, right) =
+            parse_query(&line, input.n).map_err(|e| format!("query {}: {}", query_idx, e))?;
+        let left_weight: i64 = left.iter().map(|i| input.weights[*i]).sum();
+        let right_weight: i64 = right.iter().map(|i| input.weights[*i]).sum();
+        let response = match left_weight.cmp(&right_weight) {
+            std::cmp::Ordering::Less => "<",
+            std::cmp::Ordering::Greater => ">",
+            std::cmp::Ordering::Equal => "=",
+        };
+        write_line(&mut stdin, response)?;
+    }
+    let line = session.next_line()?;
+    let answer = parse_answer(&line, input.n, input.d)?;
+    Ok(compute_score(input, &answer))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn main() {
+    let start = Instant::now();
+    let result = parse_args().and_then(|(time_limit, command)| {
+        let input = read_input()?;
+        run(&input, &command, time_limit)
+    });
+    let duration = start.elapsed().as_secs_f64();
+    match result {
+        Ok(score) => {
+            eprintln!("Score = {}", score);
+            eprintln!(
+                "result: {{\"score\": {}, \"duration\": {:.3}}}",
+                score, duration
+            );
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            eprintln!("Score = 0");
+            eprintln!(
+                "result: {{\"score\": 0, \"duration\": {:.3}, \"error\": \"{}\"}}",
+                duration,
+                escape(&error)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_numbers_rejects_non_numbers() {
+        assert_eq!(parse_numbers(" 3 0  12 "), Ok(vec![3, 0, 12]));
+        assert_eq!(parse_numbers(""), Ok(vec![]));
+        assert!(parse_numbers("1 x 2").is_err());
+        assert!(parse_numbers("1 -2").is_err());
+    }
+
+    #[test]
+    fn parse_query_splits_sides() {
+        assert_eq!(parse_query("1 2 0 3 4", 5), Ok((vec![0], vec![3, 4])));
+        assert_eq!(parse_query("2 1 4 1 2", 5), Ok((vec![4, 1], vec![2])));
+    }
+
+    #[test]
+    fn parse_query_rejects_invalid_queries() {
+        // 片側が空
+        assert!(parse_query("0 1 2", 5).is_err());
+        assert!(parse_query("1 0 2", 5).is_err());
+        // 個数が合わない
+        assert!(parse_query("1 1 0", 5).is_err());
+        assert!(parse_query("1 1 0 1 2", 5).is_err());
+        assert!(parse_query("1", 5).is_err());
+        // 個数の合計があふれる（あふれた値が行の長さと一致しても不正）
+        assert!(parse_query("18446744073709551615 3 0 1", 5).is_err());
+        assert!(parse_query("18446744073709551615 18446744073709551615 0 1", 5).is_err());
+        // 範囲外
+        assert!(parse_query("1 1 0 5", 5).is_err());
+        // 重複（同じ側、両側）
+        assert!(parse_query("2 1 0 0 1", 5).is_err());
+        assert!(parse_query("1 1 3 3", 5).is_err());
+    }
+
+    #[test]
+    fn parse_answer_checks_count_and_range() {
+        assert_eq!(parse_answer("0 1 1 0", 4, 2), Ok(vec![0, 1, 1, 0]));
+        assert!(parse_answer("0 1 1", 4, 2).is_err());
+        assert!(parse_answer("0 1 1 0 1", 4, 2).is_err());
+        assert!(parse_answer("0 1 2 0", 4, 2).is_err());
+        assert!(parse_answer("0 1 a 0", 4, 2).is_err());
+    }
+
+    #[test]
+    fn compute_score_is_one_plus_scaled_standard_deviation() {
+        let input = TesterInput {
+            n: 4,
+            d: 2,
+            q: 8,
+            weights: vec![1, 2, 3, 4],
+        };
+        // グループの重さが等しい
+        assert_eq!(compute_score(&input, &[0, 1, 1, 0]), 1);
+        // 3と7: 標準偏差2
+        assert_eq!(compute_score(&input, &[0, 0, 1, 1]), 201);
+    }
+}
@@ -1,6 +1,12 @@
 pub const TIME_LIMIT: f64 = 2.0;
 
+use crate::inference::*;
 use crate::interactor::*;
+use crate::linear_bounds::*;
+use crate::merge_insertion::*;
+use crate::rank::*;
+use crate::set_index::*;
+use crate::tabu::*;
 use crate::util::*;
 
 #[derive(Debug)]
@@ -18,10 +24,83 @@ pub enum BalanceResult {
     Unknown, // failed to get result (query limit or search failure)
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EdgeKind {
+    Query,    // interactorに問い合わせて得た辺
+    Inferred, // add_additional_edgesで推論した辺
+}
+
+/// 比較結果がどこから得られたか
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Provenance {
+    EmptySet,             // 空集合との比較
+    Cache,                // cached_resultsに保存されていた
+    Inference(Vec<u128>), // グラフの探索で推論した（証明となる経路、始点から終点まで）
+    LinearProgram,        // 比較結果の不等式から線形計画で判定した
+    Query,                // interactorに問い合わせた
+    Unavailable,          // 推論できず、クエリも残っていなかった
+}
+
+#[derive(Debug, Clone)]
+pub struct TracedResult {
+    pub result: BalanceResult,
+    pub provenance: Provenance,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProvenanceStats {
+    pub empty_set: usize,
+    pub cache: usize,
+    pub inference: usize,
+    pub linear_program: usize,
+    pub query: usize,
+    pub unavailable: usize,
+}
+
+impl ProvenanceStats {
+    fn record(&mut self, provenance: &Provenance) {
+        match provenance {
+            Provenance::EmptySet => self.empty_set += 1,
+            Provenance::Cache => self.cache += 1,
+            Provenance::Inference(_) => self.inference += 1,
+            Provenance::LinearProgram => self.linear_program += 1,
+            Provenance::Query => self.query += 1,
+            Provenance::Unavailable => self.unavailable += 1,
+        }
+    }
+
+    /// 推論の仕組みがなければ問い合わせが必要だった回数
+    pub fn saved_query_count(&self) -> usize {
+        self.cache + self.inference + self.linear_program
+    }
+
+    pub fn print(&self) {
+        eprintln!("provenance_empty_set:   {}", self.empty_set);
+        eprintln!("provenance_cache:       {}", self.cache);
+        eprintln!("provenance_inference:   {}", self.inference);
+        eprintln!("provenance_linear:      {}", self.linear_program);
+        eprintln!("provenance_query:       {}", self.query);
+        eprintln!("provenance_unavailable: {}", self.unavailable);
+        eprintln!("saved_query_count:      {}", self.saved_query_count());
+    }
+}
+
 pub struct Balancer {
     pub left_edges: FastHashMap<u128, Vec<u128>>, // first <= second
     pub right_edges: FastHashMap<u128, Vec<u128>>, // first > second
     pub cached_results: FastHashMap<(u128, u128), BalanceResult>,
+    pub edge_kinds: FastHashMap<(u128, u128), EdgeKind>, // (lighter, heavier)
+    pub comparisons: Vec<(u128, u128)>, // 問い合わせで得た(軽い方, 重い方)（evictionでも消さない）
+    pub provenance_stats: ProvenanceStats,
+    pub item_order: ItemOrder,
+    pub set_index: SetIndex,
+    pub set_index_stats: SetIndexStats,
+    pub tabu: TabuList,
+    pub linear_stats: LinearStats,
+    pub last_used: FastHashMap<u128, usize>, // 頂点が最後に使われた時刻
+    pub access_clock: usize,
+    pub evicted_node_count: usize,
+    pub eviction_count: usize,
 }
 
 impl Balancer {
@@ -30,37 +109,102 @@ impl Balancer {
             left_edges: FastHashMap::default(),
             right_edges: FastHashMap::default(),
             cached_results: FastHashMap::default(),
+            edge_kinds: FastHashMap::default(),
+            comparisons: vec![],
+            provenance_stats: ProvenanceStats::default(),
+            item_order: ItemOrder::new(),
+            set_index: SetIndex::new(),
+            set_index_stats: SetIndexStats::default(),
+            tabu: TabuList::default(),
+            linear_stats: LinearStats::default(),
+            last_used: FastHashMap::default(),
+            access_clock: 0,
+            evicted_node_count: 0,
+            eviction_count: 0,
         }
     }
 
+    ///
+    /// 比較結果を返す
+    /// 問い合わせに失敗したら（クエリを使い切った時も）、そのエラーを返す
+    ///
+    pub fn get_result(
+        &mut self,
+        left_v: &[usize],
+        right_v: &[usize],
+        interactor: &mut Interactor,
+    ) -> Result<BalanceResult, QueryError> {
+        self.try_get_traced_result(left_v, right_v, interactor)
+            .map(|traced_result| traced_result.result)
+    }
+
+    ///
+    /// get_resultと同じ手順で比較結果を求め、結果がどこから得られたかも返す
+    /// 問い合わせに失敗したら、そのエラーを返す
     ///
     /// 1. 部分集合が存在するかチェックし、存在するなら辺を引く
     /// 2. 差分が1個の集合が存在するかチェックし、存在し、かつ差分の大小関係がわかっているものに対して辺を引く
     /// 3. 元の位置から探索を開始する
     ///
-    pub fn get_result(
+    pub fn try_get_traced_result(
         &mut self,
-        left_v: &Vec<usize>,
-        right_v: &Vec<usize>,
+        left_v: &[usize],
+        right_v: &[usize],
         interactor: &mut Interactor,
-    ) -> BalanceResult {
+    ) -> Result<TracedResult, QueryError> {
+        let traced_result = self.trace_result(left_v, right_v, Some(interactor));
+        match traced_result.as_ref() {
+            Ok(traced_result) => self.provenance_stats.record(&traced_result.provenance),
+            Err(_) => self.provenance_stats.record(&Provenance::Unavailable),
+        }
+        self.evict_stale_nodes();
+        traced_result
+    }
+
+    ///
+    /// 問い合わせをせずにわかる比較結果を返す（わからなければUnknown）
+    ///
+    pub fn peek_result(&mut self, left_v: &[usize], right_v: &[usize]) -> BalanceResult {
+        let traced_result = self.trace_result(left_v, right_v, None);
+        self.evict_stale_nodes();
+        // 問い合わせをしないので失敗しない
+        traced_result.map_or(BalanceResult::Unknown, |e| e.result)
+    }
+
+    fn trace_result(
+        &mut self,
+        left_v: &[usize],
+        right_v: &[usize],
+        interactor: Option<&mut Interactor>,
+    ) -> Result<TracedResult, QueryError> {
         let check_empty_result = self.check_empty_comparison(left_v, right_v);
         if check_empty_result != BalanceResult::Unknown {
-            return check_empty_result;
+            return Ok(TracedResult {
+                result: check_empty_result,
+                provenance: Provenance::EmptySet,
+            });
         }
-        assert!(left_v.len() > 0 && right_v.len() > 0);
+        assert!(!left_v.is_empty() && !right_v.is_empty());
 
         let left_hash = self.to_hash(left_v);
         let right_hash = self.to_hash(right_v);
+        for hash in [left_hash, right_hash] {
+            if self.set_index.contains(hash) {
+                self.touch(hash);
+            }
+        }
 
         if let Some(cached_result) = self.cached_results.get(&(left_hash, right_hash)) {
-            return *cached_result;
+            return Ok(TracedResult {
+                result: *cached_result,
+                provenance: Provenance::Cache,
+            });
         }
 
         self.add_additional_edges(left_hash);
         self.add_additional_edges(right_hash);
 
-        let search_result = self.search_result(left_hash, right_hash);
+        let (search_result, path) = self.search_path(left_hash, right_hash);
         match search_result {
             BalanceResult::Unknown => {}
             BalanceResult::Left | BalanceResult::Equal => {
@@ -68,177 +212,229 @@ impl Balancer {
                     .insert((left_hash, right_hash), BalanceResult::Left);
                 self.cached_results
                     .insert((right_hash, left_hash), BalanceResult::Right);
-                return search_result;
+                self.add_item_order(left_hash, right_hash);
+                return Ok(TracedResult {
+                    result: search_result,
+                    provenance: Provenance::Inference(path),
+                });
             }
             BalanceResult::Right => {
                 self.cached_results
                     .insert((right_hash, left_hash), BalanceResult::Left);
                 self.cached_results
                     .insert((left_hash, right_hash), BalanceResult::Right);
-                return search_result;
+                self.add_item_order(right_hash, left_hash);
+                return Ok(TracedResult {
+                    result: search_result,
+                    provenance: Provenance::Inference(path),
+                });
             }
         }
-        let query_result = interactor.output_query(left_v, right_v);
+        let Some(interactor) = interactor else {
+            return Ok(TracedResult {
+                result: BalanceResult::Unknown,
+                provenance: Provenance::Unavailable,
+            });
+        };
+
+        // 問い合わせる前に、比較結果の不等式を組み合わせてわからないかを線形計画で確認する
+        let linear_result = self.linear_result(left_hash, right_hash);
+        if linear_result != BalanceResult::Unknown {
+            let (lighter_hash, heavier_hash) = if linear_result == BalanceResult::Left {
+                (left_hash, right_hash)
+            } else {
+                (right_hash, left_hash)
+            };
+            self.cached_results
+                .insert((lighter_hash, heavier_hash), BalanceResult::Left);
+            self.cached_results
+                .insert((heavier_hash, lighter_hash), BalanceResult::Right);
+            self.insert_edge(lighter_hash, heavier_hash, EdgeKind::Inferred);
+            self.add_item_order(lighter_hash, heavier_hash);
+            return Ok(TracedResult {
+                result: linear_result,
+                provenance: Provenance::LinearProgram,
+            });
+        }
+
+        let query_result = interactor.output_query(left_v, right_v)?;
 
         match query_result {
             BalanceResult::Left | BalanceResult::Equal => {
-                add_edge(&mut self.left_edges, left_hash, right_hash);
-                add_edge(&mut self.right_edges, right_hash, left_hash);
+                self.comparisons.push((left_hash, right_hash));
+                self.insert_edge(left_hash, right_hash, EdgeKind::Query);
             }
             BalanceResult::Right => {
-                add_edge(&mut self.left_edges, right_hash, left_hash);
-                add_edge(&mut self.right_edges, left_hash, right_hash);
+                self.comparisons.push((right_hash, left_hash));
+                self.insert_edge(right_hash, left_hash, EdgeKind::Query);
             }
             BalanceResult::Unknown => {}
         }
-        query_result
+        Ok(TracedResult {
+            result: query_result,
+            provenance: Provenance::Query,
+        })
+    }
+
+    pub fn search_result(&self, left_hash: u128, right_hash: u128) -> BalanceResult {
+        self.search_path(left_hash, right_hash).0
     }
 
-    fn search_result(&self, left_hash: u128, right_hash: u128) -> BalanceResult {
+    /// 探索結果と、left_hashからright_hashまでの経路を返す
+    fn search_path(&self, left_hash: u128, right_hash: u128) -> (BalanceResult, Vec<u128>) {
         // NOTE: left = rightの時は稀（だと思う）ので、ここでは無視している
         const MAX_DEPTH: u16 = 5;
 
-        fn is_reachable(
+        fn find_path(
             edges: &FastHashMap<u128, Vec<u128>>,
             from_hash: u128,
             to_hash: u128,
-        ) -> bool {
+        ) -> Option<Vec<u128>> {
             let mut q = Queue::default();
-            let mut seen = FastHashSet::default();
+            // 訪問済みの頂点と、その直前の頂点
+            let mut parent = FastHashMap::default();
             q.push_back((from_hash, 0));
-            seen.insert(from_hash);
+            parent.insert(from_hash, from_hash);
             while let Some((v, depth)) = q.pop_front() {
-                let Some(v_edges) = edges.get(&v) else { continue };
+                let Some(v_edges) = edges.get(&v) else {
+                    continue;
+                };
                 for u in v_edges {
-                    if seen.contains(u) {
+                    if parent.contains_key(u) {
                         continue;
                     }
                     if *u == to_hash {
-                        return true;
+                        let mut path = vec![to_hash, v];
+                        let mut w = v;
+                        while w != from_hash {
+                            w = parent[&w];
+                            path.push(w);
+                        }
+                        path.reverse();
+                        return Some(path);
                     }
                     if depth >= MAX_DEPTH {
                         continue;
                     }
-                    seen.insert(*u);
+                    parent.insert(*u, v);
                     q.push_back((*u, depth + 1));
                 }
             }
-            false
+            None
         }
 
-        if is_reachable(&self.left_edges, left_hash, right_hash) {
-            return BalanceResult::Left;
-        } else if is_reachable(&self.right_edges, left_hash, right_hash) {
-            return BalanceResult::Right;
-        }
-        BalanceResult::Unknown
-    }
-
-    pub fn find_lighter_in_group(&self, v: usize, groups: &Vec<usize>) -> usize {
-        let mut q = vec![];
-        let mut seen = FastHashSet::default();
-        let group_hash = self.to_hash(groups);
-        let v_hash = 1 << v;
-        q.push((v_hash, 0));
-        seen.insert(v_hash);
-
-        let mut lightest_v = v_hash;
-        let mut lightest_depth = 0;
-
-        while let Some((v, depth)) = q.pop() {
-            let Some(edges) = self.right_edges.get(&v) else { continue };
-            for u in edges {
-                if seen.contains(u) {
-                    continue;
-                }
-                if u.count_ones() == 1 && (u & group_hash) > 0 && depth > lightest_depth {
-                    lightest_depth = depth;
-                    lightest_v = *u;
-                }
-                q.push((*u, depth + 1));
-                seen.insert(*u);
-            }
+        if let Some(path) = find_path(&self.left_edges, left_hash, right_hash) {
+            return (BalanceResult::Left, path);
+        } else if let Some(path) = find_path(&self.right_edges, left_hash, right_hash) {
+            return (BalanceResult::Right, path);
         }
-
-        lightest_v.trailing_zeros() as usize
+        (BalanceResult::Unknown, vec![])
     }
 
     ///
     /// 1. 部分集合が存在するかチェックし、存在するなら辺を引く
-    /// 2. 差分が1個の集合が存在するかチェックし、存在し、かつ差分の大小関係がわかっているものに対して辺を引く
+    /// 2. 差分が小さい集合が存在するかチェックし、存在し、かつ差分の大小関係が推論できるものに対して辺を引く（infer_relation）
     ///
     fn add_additional_edges(&mut self, v_hash: u128) {
+        let start_time = time::elapsed_seconds();
+
+        let in_left_edges = self.left_edges.contains_key(&v_hash);
+        let in_right_edges = self.right_edges.contains_key(&v_hash);
+        if in_left_edges && in_right_edges {
+            return;
+        }
+
         let mut additional_edges = vec![]; // first < second
-        let edge_data = [&self.left_edges, &self.right_edges];
-        for edges in edge_data {
-            if edges.contains_key(&v_hash) {
+        let candidates = self.set_index.candidates(v_hash, MAX_INFER_DIFF);
+        for u_hash in candidates.iter() {
+            // v_hashがまだ存在しない方のグラフの頂点だけを見る
+            let is_target = (!in_left_edges && self.left_edges.contains_key(u_hash))
+                || (!in_right_edges && self.right_edges.contains_key(u_hash));
+            if !is_target {
+                continue;
+            }
+            // 部分集合のチェック
+            if (v_hash & *u_hash) == *u_hash {
+                additional_edges.push((*u_hash, v_hash));
+                continue;
+            }
+            if (v_hash & *u_hash) == v_hash {
+                additional_edges.push((v_hash, *u_hash));
                 continue;
             }
-            for (u_hash, _) in edges.iter() {
-                // 部分集合のチェック
-                if (v_hash & *u_hash) == *u_hash {
-                    additional_edges.push((*u_hash, v_hash));
-                    continue;
-                }
-                if (v_hash & *u_hash) == v_hash {
-                    additional_edges.push((v_hash, *u_hash));
-                    continue;
-                }
 
-                // 差分が1個のものをチェック
-                // 包含しているパターンは前まででチェックできている
-                // v = 010111
-                // u = 001111
-                // v ^ u = 011000
-                // a = v & u = 000111
-                // v ^ a = 010000, u ^ a = 001000
-                if (v_hash ^ *u_hash).count_ones() == 2 {
-                    let a = v_hash & *u_hash;
-                    if let Some(cached_result) = self.cached_results.get(&(v_hash ^ a, *u_hash ^ a))
-                    {
-                        match cached_result {
-                            BalanceResult::Left | BalanceResult::Equal => {
-                                additional_edges.push((v_hash, *u_hash));
-                            }
-                            BalanceResult::Right => {
-                                additional_edges.push((*u_hash, v_hash));
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        match self.search_result(v_hash ^ a, *u_hash ^ a) {
-                            BalanceResult::Left | BalanceResult::Equal => {
-                                additional_edges.push((v_hash, *u_hash));
-                            }
-                            BalanceResult::Right => {
-                                additional_edges.push((*u_hash, v_hash));
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+            // 差分の大小関係がわかっているものをチェック
+            // 包含しているパターンは前まででチェックできている
+            // v = 010111
+            // u = 001111
+            // v ^ u = 011000
+            // a = v & u = 000111
+            // v ^ a = 010000, u ^ a = 001000
+            if let Some(edge) = self.infer_relation(v_hash, *u_hash) {
+                additional_edges.push(edge);
             }
         }
 
-        // NOTE: add_edgeで重複は考慮されるので、ここで取り除く必要はない
+        self.set_index_stats.call_count += 1;
+        self.set_index_stats.candidate_count += candidates.len();
+        self.set_index_stats.node_count += self.set_index.len();
+        self.set_index_stats.max_candidate_count = self
+            .set_index_stats
+            .max_candidate_count
+            .max(candidates.len());
+
+        // NOTE: insert_edgeで重複は考慮されるので、ここで取り除く必要はない
         for (left_hash, right_hash) in additional_edges {
-            add_edge(&mut self.left_edges, left_hash, right_hash);
-            add_edge(&mut self.right_edges, right_hash, left_hash);
+            self.insert_edge(left_hash, right_hash, EdgeKind::Inferred);
+        }
+
+        self.set_index_stats.elapsed_seconds += time::elapsed_seconds() - start_time;
+    }
+
+    /// left <= right の辺を両方向のグラフに追加する
+    /// 問い合わせで得た辺は、推論で得た辺より優先して記録する
+    /// 辺の重複はedge_kindsで判定する
+    pub fn insert_edge(&mut self, left_hash: u128, right_hash: u128, kind: EdgeKind) {
+        if let Some(current_kind) = self.edge_kinds.get_mut(&(left_hash, right_hash)) {
+            if kind == EdgeKind::Query {
+                *current_kind = EdgeKind::Query;
+            }
+            return;
+        }
+        self.edge_kinds.insert((left_hash, right_hash), kind);
+        add_edge(&mut self.left_edges, left_hash, right_hash);
+        add_edge(&mut self.right_edges, right_hash, left_hash);
+        for hash in [left_hash, right_hash] {
+            if !self.set_index.contains(hash) {
+                self.set_index.insert(hash);
+                self.touch(hash);
+            }
+        }
+        self.add_item_order(left_hash, right_hash);
+    }
+
+    /// 両方が単体のアイテムなら、アイテム同士の大小関係として記録する
+    fn add_item_order(&mut self, left_hash: u128, right_hash: u128) {
+        if left_hash.count_ones() == 1 && right_hash.count_ones() == 1 {
+            self.item_order.add(
+                left_hash.trailing_zeros() as usize,
+                right_hash.trailing_zeros() as usize,
+            );
         }
     }
 
-    fn check_empty_comparison(&self, left_v: &Vec<usize>, right_v: &Vec<usize>) -> BalanceResult {
-        if left_v.len() == 0 && right_v.len() > 0 {
+    fn check_empty_comparison(&self, left_v: &[usize], right_v: &[usize]) -> BalanceResult {
+        if left_v.is_empty() && !right_v.is_empty() {
             return BalanceResult::Left;
-        } else if left_v.len() > 0 && right_v.len() == 0 {
+        } else if !left_v.is_empty() && right_v.is_empty() {
             return BalanceResult::Right;
-        } else if left_v.len() == 0 && right_v.len() == 0 {
+        } else if left_v.is_empty() && right_v.is_empty() {
             return BalanceResult::Equal;
         }
         BalanceResult::Unknown
     }
 
-    fn to_hash(&self, v: &Vec<usize>) -> u128 {
+    pub fn to_hash(&self, v: &[usize]) -> u128 {
         let mut hash = 0;
         for e in v.iter() {
             hash |= self.hash(*e);
@@ -268,16 +464,10 @@ impl Balancer {
 }
 
 fn add_edge(edges: &mut FastHashMap<u128, Vec<u128>>, first_hash: u128, second_hash: u128) {
-    if let Some(current_edges) = edges.get_mut(&first_hash) {
-        if !current_edges.contains(&second_hash) {
-            current_edges.push(second_hash);
-        }
-    } else {
-        edges.insert(first_hash, vec![second_hash]);
-    }
+    edges.entry(first_hash).or_default().push(second_hash);
 }
 
-pub fn groups_to_output_d(groups: &Vec<Vec<usize>>, input: &Input) -> Vec<usize> {
+pub fn groups_to_output_d(groups: &[Vec<usize>], input: &Input) -> Vec<usize> {
     let mut d = vec![0; input.n];
     for (g_idx, group) in groups.iter().enumerate() {
         for e in group {
@@ -287,21 +477,57 @@ pub fn groups_to_output_d(groups: &Vec<Vec<usize>>, input: &Input) -> Vec<usize>
     d
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SortMethod {
+    QuickSort,
+    MergeInsertion,
+}
+
+/// グループを軽い順に並べたrankを返す。問い合わせに失敗したら、そのエラーを返す
 pub fn sort_groups(
-    groups: &Vec<Vec<usize>>,
-    input: &Input,
+    groups: &[Vec<usize>],
+    method: SortMethod,
+    interactor: &mut Interactor,
+    balancer: &mut Balancer,
+) -> Result<Vec<usize>, QueryError> {
+    match method {
+        SortMethod::QuickSort => quick_sort_groups(groups, interactor, balancer),
+        SortMethod::MergeInsertion => {
+            // 比較関数からはエラーを返せないので、最初のエラーを覚えておき、以降は問い合わせない
+            let mut error = None;
+            let order = merge_insertion_sort((0..groups.len()).collect(), &mut |a, b| {
+                if error.is_some() {
+                    return true;
+                }
+                match balancer.get_result(&groups[a], &groups[b], interactor) {
+                    Ok(result) => result != BalanceResult::Right,
+                    Err(e) => {
+                        error = Some(e);
+                        true
+                    }
+                }
+            });
+            match error {
+                Some(e) => Err(e),
+                None => Ok(order),
+            }
+        }
+    }
+}
+
+fn quick_sort_groups(
+    groups: &[Vec<usize>],
     interactor: &mut Interactor,
     balancer: &mut Balancer,
-) -> Vec<usize> {
+) -> Result<Vec<usize>, QueryError> {
     fn q_sort(
         targets: Vec<usize>,
-        groups: &Vec<Vec<usize>>,
-        input: &Input,
+        groups: &[Vec<usize>],
         interactor: &mut Interactor,
         balancer: &mut Balancer,
-    ) -> Vec<usize> {
+    ) -> Result<Vec<usize>, QueryError> {
         if targets.len() <= 1 {
-            return targets;
+            return Ok(targets);
         }
         let pivot_g_idx = targets[rnd::gen_range(0, targets.len())];
         let mut left_targets = vec![pivot_g_idx];
@@ -310,40 +536,47 @@ pub fn sort_groups(
             if g_idx == pivot_g_idx {
                 continue;
             }
-            match balancer.get_result(&groups[pivot_g_idx], &groups[g_idx], interactor) {
+            match balancer.get_result(&groups[pivot_g_idx], &groups[g_idx], interactor)? {
                 BalanceResult::Left => right_targets.push(g_idx), // <
                 BalanceResult::Right => left_targets.push(g_idx), // >
                 BalanceResult::Equal => right_targets.push(g_idx), // =
                 BalanceResult::Unknown => break,
             }
         }
-        [
-            q_sort(left_targets, groups, input, interactor, balancer),
-            q_sort(right_targets, groups, input, interactor, balancer),
+        Ok([
+            q_sort(left_targets, groups, interactor, balancer)?,
+            q_sort(right_targets, groups, interactor, balancer)?,
         ]
-        .concat()
+        .concat())
     }
 
-    q_sort(
-        (0..groups.len()).collect(),
-        groups,
-        input,
-        interactor,
-        balancer,
-    )
+    q_sort((0..groups.len()).collect(), groups, interactor, balancer)
 }
 
+///
+/// グループの重さが変わった時に順位を更新する
+///
+/// from_up: rank[heavier_g_idx]のグループが軽くなった（falseならrank[lighter_g_idx]のグループが重くなった）
+/// 比較できなかった場合はfalseを返す（その周りの大小関係は確認済みでないままになる）
+/// 問い合わせに失敗したら、そのエラーを返す
+///
+#[allow(clippy::too_many_arguments)]
 pub fn update_rank(
-    rank: &mut Vec<usize>,
-    groups: &Vec<Vec<usize>>,
+    rank: &mut Rank,
+    groups: &[Vec<usize>],
     from_up: bool,
     lighter_g_idx: usize,
     heaviest_g_idx: usize,
     input: &Input,
     interactor: &mut Interactor,
     balancer: &mut Balancer,
-) -> bool {
+) -> Result<bool, QueryError> {
     const PAR: usize = 8; // :param
+    if from_up {
+        rank.invalidate_below(rank[heaviest_g_idx]);
+    } else {
+        rank.invalidate_above(rank[lighter_g_idx]);
+    }
     if input.d < PAR {
         update_rank_linear_search(
             rank,
@@ -368,65 +601,79 @@ pub fn update_rank(
 }
 
 pub fn update_rank_linear_search(
-    rank: &mut Vec<usize>,
-    groups: &Vec<Vec<usize>>,
+    rank: &mut Rank,
+    groups: &[Vec<usize>],
     from_up: bool,
     lighter_g_idx: usize,
     heavier_g_idx: usize,
     interactor: &mut Interactor,
     balancer: &mut Balancer,
-) -> bool {
+) -> Result<bool, QueryError> {
     if from_up {
         for i in (lighter_g_idx..heavier_g_idx).rev() {
-            match balancer.get_result(&groups[rank[i]], &groups[rank[i + 1]], interactor) {
-                BalanceResult::Left => break,                // <
-                BalanceResult::Right => rank.swap(i, i + 1), // >
-                BalanceResult::Equal => break,               // =
-                BalanceResult::Unknown => return false,
+            match balancer.get_result(&groups[rank[i]], &groups[rank[i + 1]], interactor)? {
+                BalanceResult::Left | BalanceResult::Equal => {
+                    // <, =
+                    rank.set_certain(i);
+                    break;
+                }
+                BalanceResult::Right => rank.swap(i), // >
+                BalanceResult::Unknown => return Ok(false),
             }
         }
     } else {
         for i in lighter_g_idx..heavier_g_idx {
-            match balancer.get_result(&groups[rank[i]], &groups[rank[i + 1]], interactor) {
-                BalanceResult::Left => break,                // <
-                BalanceResult::Right => rank.swap(i, i + 1), // >
-                BalanceResult::Equal => break,               // =
-                BalanceResult::Unknown => return false,
+            match balancer.get_result(&groups[rank[i]], &groups[rank[i + 1]], interactor)? {
+                BalanceResult::Left | BalanceResult::Equal => {
+                    // <, =
+                    rank.set_certain(i);
+                    break;
+                }
+                BalanceResult::Right => rank.swap(i), // >
+                BalanceResult::Unknown => return Ok(false),
             }
         }
     }
-    true
+    Ok(true)
 }
 
 pub fn update_rank_binary_search(
-    rank: &mut Vec<usize>,
-    groups: &Vec<Vec<usize>>,
+    rank: &mut Rank,
+    groups: &[Vec<usize>],
     from_up: bool,
     lighter_g_idx: usize,
     heavier_g_idx: usize,
     interactor: &mut Interactor,
     balancer: &mut Balancer,
-) -> bool {
+) -> Result<bool, QueryError> {
     let move_g_idx = if from_up {
         heavier_g_idx
     } else {
         lighter_g_idx
     };
-    let move_g = rank[move_g_idx];
-    rank.remove(move_g_idx);
+    let move_g = rank.remove(move_g_idx);
     let mut l = lighter_g_idx as i32 - 1;
     let mut r = heavier_g_idx as i32;
     while r - l > 1 {
         let m = (l + r) / 2;
-        match balancer.get_result(&groups[rank[m as usize]], &groups[move_g], interactor) {
-            BalanceResult::Left | BalanceResult::Equal => l = m, // <
-            BalanceResult::Right => r = m,                       // >
-            BalanceResult::Unknown => {
+        let result = balancer.get_result(&groups[rank[m as usize]], &groups[move_g], interactor);
+        match result {
+            Ok(BalanceResult::Left | BalanceResult::Equal) => l = m, // <
+            Ok(BalanceResult::Right) => r = m,                       // >
+            Ok(BalanceResult::Unknown) | Err(_) => {
+                // 比較できなかったので元の位置に戻す
                 rank.insert(move_g_idx, move_g);
-                return false;
+                return result.map(|_| false);
             }
         }
     }
     rank.insert(r as usize, move_g);
-    true
+    // 比較した両隣との大小関係は確認済み
+    if l >= lighter_g_idx as i32 {
+        rank.set_certain(l as usize);
+    }
+    if r < heavier_g_idx as i32 {
+        rank.set_certain(r as usize);
+    }
+    Ok(true)
 }
@@ -0,0 +1,320 @@
+use crate::action::*;
+use crate::def::*;
+use crate::estimator::*;
+use crate::group_state::*;
+use crate::interactor::*;
+use crate::rank::*;
+use crate::util::*;
+
+/// 終盤に推定値を更新する時のサンプリングの周回数
+const ENDGAME_SWEEP_COUNT: usize = 20; // :param
+/// 終盤の処理を打ち切る時刻（秒）
+const ENDGAME_TIME_LIMIT: f64 = TIME_LIMIT - 0.05;
+/// 終盤の処理を始める時刻（秒）。これ以降はメインループを回さない
+pub const ENDGAME_START_TIME: f64 = TIME_LIMIT - 0.3; // :param
+/// 終盤の処理のために残しておくクエリ数（Qに対する割合）
+const ENDGAME_QUERY_RATIO: f64 = 0.05; // :param
+
+/// メインループで使わずに、終盤の処理のために残しておくクエリ数
+pub fn endgame_query_budget(input: &Input) -> usize {
+    (input.q as f64 * ENDGAME_QUERY_RATIO).ceil() as usize
+}
+
+/// 重いグループから軽いグループへの変更の候補
+struct Candidate {
+    benefit: f64,               // 推定した重さの差の縮まり方
+    item_indices_a: Vec<usize>, // 軽いグループから出すアイテム（moveなら空）
+    item_indices_b: Vec<usize>, // 重いグループから出すアイテム
+}
+
+///
+/// 残ったクエリで、一番重いグループと一番軽いグループの間のmoveと1-1のswapを全て確認する
+///
+/// 1. 一番軽い・重いグループの順位を確認する
+/// 2. 推定した重さで、差が縮まりそうな候補を縮まり方の大きい順に並べる
+/// 3. 順に比較で確認し、採用できたら1.からやり直す（線形計画の重さの範囲から確かに不採用になる候補は飛ばす）
+/// 4. 全ての候補が不採用なら、順位の離れた組から順に、他のグループの組でも同じことをする
+/// 5. それでも全て不採用なら、2個のアイテムを動かす候補も加えて1.からやり直す
+///
+/// 推定値は最初に1回だけ更新する（アイテムの重さの推定はグループの構成によらない）
+///
+/// reserved_query_count個のクエリは残しておく
+/// 採用した回数をadopted_countに足す（問い合わせに失敗した場合も、それまでに採用した分は数える）
+///
+#[allow(clippy::too_many_arguments)]
+pub fn run_endgame(
+    input: &Input,
+    reserved_query_count: usize,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    estimator: &mut McmcEstimator,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+    adopted_count: &mut usize,
+) -> Result<(), QueryError> {
+    let d = input.d;
+    let has_budget = |interactor: &Interactor| {
+        interactor.query_count + reserved_query_count < input.q
+            && time::elapsed_seconds() < ENDGAME_TIME_LIMIT
+    };
+    // 順位の差が大きい組から並べる
+    let mut pairs: Vec<(usize, usize)> = (0..d)
+        .flat_map(|i| (i + 1..d).map(move |j| (i, j)))
+        .collect();
+    pairs.sort_by_key(|(i, j)| (d - (j - i), *i));
+    let mut is_wide = false;
+    estimator.update(balancer, ENDGAME_SWEEP_COUNT);
+    'endgame: while has_budget(interactor) {
+        for &(lighter_g_idx, heavier_g_idx) in pairs.iter() {
+            if try_pair(
+                lighter_g_idx,
+                heavier_g_idx,
+                is_wide,
+                &has_budget,
+                input,
+                groups,
+                rank,
+                estimator,
+                balancer,
+                interactor,
+            )? {
+                *adopted_count += 1;
+                eprintln!("[{} / {}] adopt endgame", interactor.query_count, input.q);
+                // 構成が変わったグループについての記録は使われないので消す
+                balancer.expire_tabu(groups);
+                continue 'endgame;
+            }
+            if !has_budget(interactor) {
+                break 'endgame;
+            }
+        }
+        if is_wide {
+            break;
+        }
+        is_wide = true;
+    }
+    Ok(())
+}
+
+/// 1組のグループの間の候補を順に確認し、採用できたらtrueを返す
+#[allow(clippy::too_many_arguments)]
+fn try_pair(
+    lighter_g_idx: usize,
+    heavier_g_idx: usize,
+    is_wide: bool,
+    has_budget: &impl Fn(&Interactor) -> bool,
+    input: &Input,
+    groups: &mut GroupState,
+    rank: &mut dyn Ranking,
+    estimator: &McmcEstimator,
+    balancer: &mut Balancer,
+    interactor: &mut Interactor,
+) -> Result<bool, QueryError> {
+    while !rank.is_position_certain(lighter_g_idx) || !rank.is_position_certain(heavier_g_idx) {
+        // 全て確認済みでも確かにならない順位（トーナメントの中間）は使わない
+        let g_idx = if rank.is_position_certain(lighter_g_idx) {
+            heavier_g_idx
+        } else {
+            lighter_g_idx
+        };
+        if rank.stale_count() == 0
+            || !has_budget(interactor)
+            || !rank.repair_position(groups, g_idx, 1, interactor, balancer)?
+        {
+            return Ok(false);
+        }
+    }
+    let (lightest, heaviest) = (
+        groups[rank[lighter_g_idx]].clone(),
+        groups[rank[heavier_g_idx]].clone(),
+    );
+    let candidates = list_candidates(&lightest, &heaviest, is_wide, estimator);
+    for candidate in candidates {
+        if !has_budget(interactor) {
+            return Ok(false);
+        }
+        // 重さの範囲から確かに不採用になる候補は、問い合わせずに飛ばす
+        if balancer.is_exchange_certainly_rejected(
+            &candidate.item_indices_a,
+            &candidate.item_indices_b,
+            &lightest,
+            &heaviest,
+        ) {
+            continue;
+        }
+        if !candidate.item_indices_a.is_empty() {
+            match balancer.get_result(
+                &candidate.item_indices_a,
+                &candidate.item_indices_b,
+                interactor,
+            )? {
+                BalanceResult::Left => {}
+                _ => continue,
+            }
+        }
+        if exchange_items(
+            heavier_g_idx,
+            lighter_g_idx,
+            &candidate.item_indices_a,
+            &candidate.item_indices_b,
+            groups,
+            rank,
+            input,
+            balancer,
+            interactor,
+        )? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+///
+/// 候補を、推定した重さでの差の縮まり方が大きい順に返す（推定では縮まらないものも含める）
+/// is_wideなら、重い方から2個出すものと、軽い方から2個出すswapのうち、推定で縮まるものも加える
+///
+fn list_candidates(
+    lightest: &[usize],
+    heaviest: &[usize],
+    is_wide: bool,
+    estimator: &dyn WeightEstimator,
+) -> Vec<Candidate> {
+    let gap = estimator.group_weight(heaviest) - estimator.group_weight(lightest);
+    // 重い方からtだけ軽い方に移すと、差はgapから|gap - 2t|になる
+    let benefit = |t: f64| gap - (gap - 2. * t).abs();
+    let mut candidates = vec![];
+    for &b in heaviest.iter() {
+        let w_b = estimator.weight(b);
+        candidates.push(Candidate {
+            benefit: benefit(w_b),
+            item_indices_a: vec![],
+            item_indices_b: vec![b],
+        });
+        for &a in lightest.iter() {
+            candidates.push(Candidate {
+                benefit: benefit(w_b - estimator.weight(a)),
+                item_indices_a: vec![a],
+                item_indices_b: vec![b],
+            });
+        }
+    }
+    if is_wide {
+        let mut push_if_better = |item_indices_a: Vec<usize>, item_indices_b: Vec<usize>| {
+            // 取り除いた後のグループが空になるものは比べられない
+            if item_indices_a.len() >= lightest.len() || item_indices_b.len() >= heaviest.len() {
+                return;
+            }
+            let t =
+                estimator.group_weight(&item_indices_b) - estimator.group_weight(&item_indices_a);
+            if benefit(t) > 0. {
+                candidates.push(Candidate {
+                    benefit: benefit(t),
+                    item_indices_a,
+                    item_indices_b,
+                });
+            }
+        };
+        for (i, &b1) in heaviest.iter().enumerate() {
+            for &b2 in heaviest[i + 1..].iter() {
+                push_if_better(vec![], vec![b1, b2]);
+                for &a in lightest.iter() {
+                    push_if_better(vec![a], vec![b1, b2]);
+                }
+            }
+        }
+        for (i, &a1) in lightest.iter().enumerate() {
+            for &a2 in lightest[i + 1..].iter() {
+                for &b in heaviest.iter() {
+                    push_if_better(vec![a1, a2], vec![b]);
+                }
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.benefit.total_cmp(&a.benefit));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 決まった重さを返す推定器
+    struct StubEstimator(Vec<f64>);
+
+    impl WeightEstimator for StubEstimator {
+        fn weight(&self, i: usize) -> f64 {
+            self.0[i]
+        }
+    }
+
+    fn sizes(candidate: &Candidate) -> (usize, usize) {
+        (
+            candidate.item_indices_a.len(),
+            candidate.item_indices_b.len(),
+        )
+    }
+
+    #[test]
+    fn candidates_are_sorted_by_benefit() {
+        // 差は3。重い方から軽い方にtだけ移すと、差の縮まり方は3 - |3 - 2t|
+        let estimator = StubEstimator(vec![1., 6., 2., 8.]);
+        let candidates = list_candidates(&[0, 1], &[2, 3], false, &estimator);
+        // move 2通りと1-1のswap 4通り
+        assert_eq!(candidates.len(), 6);
+        assert!(candidates
+            .iter()
+            .all(|c| matches!(sizes(c), (0, 1) | (1, 1))));
+        assert!(candidates.windows(2).all(|w| w[0].benefit >= w[1].benefit));
+        // 一番良いのはアイテム2のmove（t = 2で差は1。1と3のswapも同じだが、並べ替えは安定なので先に作ったmoveが先）
+        assert_eq!(candidates[0].item_indices_a, Vec::<usize>::new());
+        assert_eq!(candidates[0].item_indices_b, vec![2]);
+        assert!((candidates[0].benefit - 2.).abs() < 1e-9);
+        // 推定では縮まらない候補も残す（アイテム3のmoveはt = 8で差は13）
+        let worst = candidates.last().unwrap();
+        assert!(worst.item_indices_a.is_empty());
+        assert_eq!(worst.item_indices_b, vec![3]);
+        assert!((worst.benefit - -10.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wide_candidates_are_filtered_by_benefit() {
+        let estimator = StubEstimator(vec![1., 1., 1., 3., 4., 5.]);
+        let narrow = list_candidates(&[0, 1, 2], &[3, 4, 5], false, &estimator);
+        let wide = list_candidates(&[0, 1, 2], &[3, 4, 5], true, &estimator);
+        // 1-1までの候補は全て含み、2個動かす候補は推定で縮まるものだけ加える
+        assert!(wide.len() > narrow.len());
+        let single_count = wide
+            .iter()
+            .filter(|c| matches!(sizes(c), (0, 1) | (1, 1)))
+            .count();
+        assert_eq!(single_count, narrow.len());
+        for candidate in wide.iter() {
+            if !matches!(sizes(candidate), (0, 1) | (1, 1)) {
+                assert!(candidate.benefit > 0.);
+            }
+        }
+        assert!(wide.windows(2).all(|w| w[0].benefit >= w[1].benefit));
+        // 差は9なので、3と4を移すと差は5に縮まり、4と5を移すと差は9のまま
+        assert!(wide
+            .iter()
+            .any(|c| c.item_indices_a.is_empty() && c.item_indices_b == vec![3, 4]));
+        assert!(!wide
+            .iter()
+            .any(|c| c.item_indices_a.is_empty() && c.item_indices_b == vec![4, 5]));
+    }
+
+    #[test]
+    fn wide_candidates_never_empty_a_group() {
+        // 軽い方も重い方も2個なので、2個出す候補は作れない
+        let estimator = StubEstimator(vec![1., 1., 6., 7.]);
+        let candidates = list_candidates(&[0, 1], &[2, 3], true, &estimator);
+        assert!(candidates
+            .iter()
+            .all(|c| c.item_indices_a.len() < 2 && c.item_indices_b.len() < 2));
+        // 重い方が3個なら、重い方から2個出す候補は作れる
+        let estimator = StubEstimator(vec![1., 1., 6., 7., 8.]);
+        let candidates = list_candidates(&[0, 1], &[2, 3, 4], true, &estimator);
+        assert!(candidates.iter().any(|c| c.item_indices_b.len() == 2));
+        assert!(candidates.iter().all(|c| c.item_indices_a.len() < 2));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::def::*;
+use std::collections::VecDeque;
+
+use crate::inference::*;
+use crate::score_eval::*;
+use crate::util::*;
+
+/// 提案の歩幅（重さの対数に足す正規乱数の標準偏差）
+const STEP_SIZE: f64 = 0.5; // :param
+/// 平均・分散を求める時に、古いサンプルの重みを減らす割合
+const SAMPLE_DECAY: f64 = 0.95; // :param
+/// 制約の不足分に対する罰金（対数尤度の減少量）
+const VIOLATION_PENALTY: f64 = 100.; // :param
+/// スコアの分布を求めるために残しておく、最近のサンプルの数
+const RECENT_SAMPLE_COUNT: usize = 32; // :param
+
+///
+/// アイテムの重さの推定値（重さは平均が1になる単位）
+///
+pub trait WeightEstimator {
+    fn weight(&self, i: usize) -> f64;
+
+    fn group_weight(&self, group: &[usize]) -> f64 {
+        group.iter().map(|i| self.weight(*i)).sum()
+    }
+
+    /// アイテムの重さの推定値の分散（分散を推定しなければNone）
+    #[allow(unused)]
+    fn variance(&self, _i: usize) -> Option<f64> {
+        None
+    }
+
+    /// proposalの方がcurrentよりスコアが良くなる確率（サンプルを持たなければNone）
+    fn improvement_probability(
+        &self,
+        _current: &[Vec<usize>],
+        _proposal: &[Vec<usize>],
+    ) -> Option<f64> {
+        None
+    }
+}
+
+///
+/// 比較結果と矛盾しない重さをMCMCでサンプリングし、各アイテムの重さの事後平均・分散を推定する
+///
+/// - 事前分布は平均1の指数分布（入力の生成方法と同じく、N/Dを超える重さは生成されない）
+/// - 問い合わせで得たw(L) <= w(R)を制約とし、満たしていない分に比例する罰金を課す
+///   （厳密な制約にすると、初期状態から制約を全て満たす状態にたどり着くのが難しい）
+/// - 1アイテムずつ、重さの対数にランダムな値を足す提案をする（Metropolis-within-Gibbs）
+///
+/// 重さは平均が1になる単位で表す（実際の重さとは定数倍の違いがある）
+///
+pub struct McmcEstimator {
+    max_weight: f64,
+    weights: Vec<f64>,                        // 現在のサンプル
+    constraints: Vec<(u128, u128)>,           // (軽い方, 重い方)
+    slack: Vec<f64>,                          // slack[c]: w(重い方) - w(軽い方)
+    item_constraints: Vec<Vec<(usize, f64)>>, // (制約, 重い方なら1、軽い方なら-1)
+    violation: f64,                           // 満たしていない制約の不足分の和
+    sum_weights: Vec<f64>,
+    sum_squared_weights: Vec<f64>,
+    sample_count: f64,
+    recent_samples: VecDeque<Vec<f64>>,
+    step_count: usize,
+    accepted_count: usize,
+}
+
+impl McmcEstimator {
+    pub fn new(input: &Input) -> McmcEstimator {
+        McmcEstimator {
+            max_weight: input.n as f64 / input.d as f64,
+            weights: (0..input.n)
+                .map(|_| (-(1. - rnd::nextf()).ln()).min(input.n as f64 / input.d as f64))
+                .collect(),
+            constraints: vec![],
+            slack: vec![],
+            item_constraints: vec![vec![]; input.n],
+            violation: 0.,
+            sum_weights: vec![0.; input.n],
+            sum_squared_weights: vec![0.; input.n],
+            sample_count: 0.,
+            recent_samples: VecDeque::new(),
+            step_count: 0,
+            accepted_count: 0,
+        }
+    }
+
+    ///
+    /// balancerに新しく記録された比較結果を制約に加えて、sweep_count周サンプリングする
+    /// 1周で全てのアイテムの重さを1回ずつ更新する
+    ///
+    pub fn update(&mut self, balancer: &Balancer, sweep_count: usize) {
+        self.sync_constraints(balancer);
+        for _ in 0..sweep_count {
+            for i in 0..self.weights.len() {
+                self.step(i);
+            }
+            self.record_sample();
+        }
+    }
+
+    fn sync_constraints(&mut self, balancer: &Balancer) {
+        if balancer.comparisons.len() == self.constraints.len() {
+            return;
+        }
+        for &(lighter_hash, heavier_hash) in balancer.comparisons[self.constraints.len()..].iter() {
+            let c = self.constraints.len();
+            self.constraints.push((lighter_hash, heavier_hash));
+            self.slack.push(0.);
+            for i in iter_bits(lighter_hash) {
+                self.item_constraints[i].push((c, -1.));
+            }
+            for i in iter_bits(heavier_hash) {
+                self.item_constraints[i].push((c, 1.));
+            }
+        }
+        // 誤差が溜まらないように、全ての制約の余裕を計算し直す
+        self.violation = 0.;
+        for c in 0..self.constraints.len() {
+            let (lighter_hash, heavier_hash) = self.constraints[c];
+            self.slack[c] = self.hash_weight(heavier_hash) - self.hash_weight(lighter_hash);
+            self.violation += (-self.slack[c]).max(0.);
+        }
+    }
+
+    fn step(&mut self, i: usize) {
+        self.step_count += 1;
+        let w = self.weights[i];
+        let new_w = w * (STEP_SIZE * rnd::gen_normal()).exp();
+        if new_w > self.max_weight {
+            return;
+        }
+        let delta = new_w - w;
+        let mut violation_delta = 0.;
+        for &(c, coef) in self.item_constraints[i].iter() {
+            let s = self.slack[c];
+            violation_delta += (-(s + coef * delta)).max(0.) - (-s).max(0.);
+        }
+        // 事前分布 exp(-w) と、対数空間での提案による補正 new_w / w と、制約の罰金
+        let log_ratio = -delta + (new_w / w).ln() - VIOLATION_PENALTY * violation_delta;
+        if rnd::nextf().ln() >= log_ratio {
+            return;
+        }
+        for &(c, coef) in self.item_constraints[i].iter() {
+            self.slack[c] += coef * delta;
+        }
+        self.violation = (self.violation + violation_delta).max(0.);
+        self.weights[i] = new_w;
+        self.accepted_count += 1;
+    }
+
+    fn record_sample(&mut self) {
+        self.sample_count = self.sample_count * SAMPLE_DECAY + 1.;
+        for i in 0..self.weights.len() {
+            let w = self.weights[i];
+            self.sum_weights[i] = self.sum_weights[i] * SAMPLE_DECAY + w;
+            self.sum_squared_weights[i] = self.sum_squared_weights[i] * SAMPLE_DECAY + w * w;
+        }
+        if self.recent_samples.len() == RECENT_SAMPLE_COUNT {
+            self.recent_samples.pop_front();
+        }
+        self.recent_samples.push_back(self.weights.clone());
+    }
+
+    /// 最近のサンプルでの、グループ分けのスコアの分布
+    pub fn score_distribution(&self, groups: &[Vec<usize>]) -> Option<ScoreDistribution> {
+        let samples: Vec<&[f64]> = self.recent_samples.iter().map(|w| w.as_slice()).collect();
+        evaluate_score(&samples, groups)
+    }
+
+    fn hash_weight(&self, hash: u128) -> f64 {
+        iter_bits(hash).map(|i| self.weights[i]).sum()
+    }
+
+    /// サンプリングを一度でもしたか（していなければ、推定値は初期値のまま）
+    #[allow(unused)]
+    pub fn has_samples(&self) -> bool {
+        self.sample_count > 0.
+    }
+
+    /// 現在のサンプル
+    #[allow(unused)]
+    pub fn sample(&self) -> &[f64] {
+        &self.weights
+    }
+
+    pub fn print(&self) {
+        eprintln!("mcmc_constraint_count:  {}", self.constraints.len());
+        eprintln!("mcmc_step_count:        {}", self.step_count);
+        eprintln!(
+            "mcmc_acceptance_rate:   {:.3}",
+            self.accepted_count as f64 / self.step_count.max(1) as f64
+        );
+        eprintln!("mcmc_violation:         {:.3}", self.violation);
+    }
+}
+
+impl WeightEstimator for McmcEstimator {
+    /// アイテムの重さの推定値（事後平均）
+    fn weight(&self, i: usize) -> f64 {
+        if self.sample_count == 0. {
+            return self.weights[i];
+        }
+        self.sum_weights[i] / self.sample_count
+    }
+
+    /// アイテムの重さの事後分散
+    fn variance(&self, i: usize) -> Option<f64> {
+        if self.sample_count == 0. {
+            return None;
+        }
+        let mean = self.weight(i);
+        Some((self.sum_squared_weights[i] / self.sample_count - mean * mean).max(0.))
+    }
+
+    fn improvement_probability(
+        &self,
+        current: &[Vec<usize>],
+        proposal: &[Vec<usize>],
+    ) -> Option<f64> {
+        let samples: Vec<&[f64]> = self.recent_samples.iter().map(|w| w.as_slice()).collect();
+        improvement_probability(&samples, current, proposal)
+    }
+}
@@ -0,0 +1,193 @@
+//! feature = "local"でだけ使う（提出時には使われない）
+
+use crate::estimator::*;
+use crate::json::*;
+use crate::verifier::*;
+
+/// 精度を記録する間隔（クエリ数全体に対する割合）
+const CHECKPOINT_INTERVAL_RATIO: f64 = 0.1;
+
+/// 1つの推定器の、ある時点での真の重さに対する精度
+#[derive(Debug, Clone)]
+pub struct EstimatorAccuracy {
+    pub spearman: f64,            // 順位相関
+    pub scaled_rmse: f64, // 最小二乗で定数倍を合わせた後の二乗平均誤差（真の重さの平均に対する割合）
+    pub calibration: Option<f64>, // 誤差の二乗 / 推定した分散 の平均（1に近いほど分散が正しい）
+    pub coverage: Option<f64>, // 誤差が推定した標準偏差以内のアイテムの割合（正規分布なら約0.68）
+}
+
+impl EstimatorAccuracy {
+    ///
+    /// 推定値は平均1の単位なので、真の重さと定数倍の違いがある
+    /// 誤差 s * e - w が最小になる倍率 s = Σew / Σe^2 で合わせてから、誤差と分散を比べる
+    ///
+    pub fn measure(true_weights: &[i64], estimator: &dyn WeightEstimator) -> EstimatorAccuracy {
+        let n = true_weights.len();
+        let truth: Vec<f64> = true_weights.iter().map(|w| *w as f64).collect();
+        let estimate: Vec<f64> = (0..n).map(|i| estimator.weight(i)).collect();
+
+        let spearman = pearson(&to_ranks(&truth), &to_ranks(&estimate));
+
+        let scale = dot(&estimate, &truth) / dot(&estimate, &estimate).max(f64::MIN_POSITIVE);
+        let errors: Vec<f64> = (0..n).map(|i| scale * estimate[i] - truth[i]).collect();
+        let mean_truth = truth.iter().sum::<f64>() / n as f64;
+        let scaled_rmse = (dot(&errors, &errors) / n as f64).sqrt() / mean_truth;
+
+        // 分散が0のアイテム（上限に張り付いているなど）は比べられないので除く
+        let variances: Option<Vec<f64>> = (0..n).map(|i| estimator.variance(i)).collect();
+        let z2: Vec<f64> = variances.map_or(vec![], |variances| {
+            (0..n)
+                .filter(|i| variances[*i] > 0.)
+                .map(|i| errors[i] * errors[i] / (scale * scale * variances[i]))
+                .collect()
+        });
+        let (calibration, coverage) = if z2.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(z2.iter().sum::<f64>() / z2.len() as f64),
+                Some(z2.iter().filter(|e| **e <= 1.).count() as f64 / z2.len() as f64),
+            )
+        };
+
+        EstimatorAccuracy {
+            spearman,
+            scaled_rmse,
+            calibration,
+            coverage,
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let optional = |v: Option<f64>| v.map_or(JsonValue::Null, JsonValue::Number);
+        JsonValue::Object(vec![
+            ("spearman".to_owned(), JsonValue::Number(self.spearman)),
+            (
+                "scaled_rmse".to_owned(),
+                JsonValue::Number(self.scaled_rmse),
+            ),
+            ("calibration".to_owned(), optional(self.calibration)),
+            ("coverage".to_owned(), optional(self.coverage)),
+        ])
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// 値の順位（0始まり、同じ値には平均の順位を付ける）
+fn to_ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.; values.len()];
+    let mut begin = 0;
+    while begin < order.len() {
+        let mut end = begin + 1;
+        while end < order.len() && values[order[end]] == values[order[begin]] {
+            end += 1;
+        }
+        for &i in order[begin..end].iter() {
+            ranks[i] = (begin + end - 1) as f64 / 2.;
+        }
+        begin = end;
+    }
+    ranks
+}
+
+/// ピアソンの相関係数（どちらかが定数なら0）
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut cov, mut var_a, mut var_b) = (0., 0., 0.);
+    for (x, y) in a.iter().zip(b.iter()) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a == 0. || var_b == 0. {
+        return 0.;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+///
+/// 推定器の精度を、クエリ数の一定の割合ごとと最後に、真の重さと比べて記録する（ローカル用）
+///
+/// スコアが悪い時に、推定が悪いのか最適化が悪いのかを切り分けるために使う
+/// 最後にJSONで出力し、run.pyで結果の記録に加える
+///
+pub struct EstimatorReport {
+    weights: Vec<i64>,
+    next_checkpoint: usize,
+    checkpoints: Vec<(usize, &'static str, EstimatorAccuracy)>, // (クエリ数, 推定器の名前, 精度)
+}
+
+impl EstimatorReport {
+    pub fn from_env() -> Option<EstimatorReport> {
+        Some(EstimatorReport {
+            weights: read_true_weights()?,
+            next_checkpoint: 0,
+            checkpoints: vec![],
+        })
+    }
+
+    /// 前回記録してから、クエリを一定の割合以上使ったか
+    pub fn is_due(&self, query_count: usize) -> bool {
+        query_count >= self.next_checkpoint
+    }
+
+    /// 各推定器の精度を記録する。記録した時点から次の記録までの間隔を空ける
+    pub fn record(
+        &mut self,
+        query_count: usize,
+        q: usize,
+        estimators: &[(&'static str, &dyn WeightEstimator)],
+    ) {
+        let format_optional = |v: Option<f64>| v.map_or("none".to_owned(), |v| format!("{:.3}", v));
+        for &(name, estimator) in estimators.iter() {
+            let accuracy = EstimatorAccuracy::measure(&self.weights, estimator);
+            eprintln!(
+                "[{} / {}] estimator_accuracy {}: spearman {:.3}, scaled_rmse {:.3}, calibration {}, coverage {}",
+                query_count,
+                q,
+                name,
+                accuracy.spearman,
+                accuracy.scaled_rmse,
+                format_optional(accuracy.calibration),
+                format_optional(accuracy.coverage),
+            );
+            self.checkpoints.push((query_count, name, accuracy));
+        }
+        self.next_checkpoint =
+            query_count + ((q as f64 * CHECKPOINT_INTERVAL_RATIO).ceil() as usize).max(1);
+    }
+
+    ///
+    /// 推定器ごとの最後の精度と、全ての記録をJSONで出力する
+    /// run.pyは`estimator_accuracy:`で始まる行を読む
+    ///
+    pub fn print(&self) {
+        let mut last: Vec<(String, JsonValue)> = vec![];
+        for (_, name, accuracy) in self.checkpoints.iter() {
+            last.retain(|(k, _)| k != name);
+            last.push((name.to_string(), accuracy.to_json()));
+        }
+        let checkpoints = self
+            .checkpoints
+            .iter()
+            .map(|(query_count, name, accuracy)| {
+                JsonValue::Object(vec![
+                    ("query".to_owned(), JsonValue::Number(*query_count as f64)),
+                    ("estimator".to_owned(), JsonValue::String(name.to_string())),
+                    ("accuracy".to_owned(), accuracy.to_json()),
+                ])
+            })
+            .collect();
+        let json = JsonValue::Object(vec![
+            ("last".to_owned(), JsonValue::Object(last)),
+            ("checkpoints".to_owned(), JsonValue::Array(checkpoints)),
+        ]);
+        eprintln!("estimator_accuracy: {}", json.to_json_string());
+    }
+}
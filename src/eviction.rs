@@ -0,0 +1,217 @@
+use crate::def::*;
+use crate::util::*;
+
+/// グラフの頂点数の上限（超えたらEVICT_TARGET_NODE_COUNTまで減らす）
+const MAX_NODE_COUNT: usize = 2048; // :param
+const EVICT_TARGET_NODE_COUNT: usize = 1536; // :param
+
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStats {
+    pub node_count: usize,
+    pub edge_count: usize,
+    pub cached_result_count: usize,
+    pub evicted_node_count: usize,
+    pub eviction_count: usize,
+    pub approx_bytes: usize,
+}
+
+impl MemoryStats {
+    pub fn print(&self) {
+        eprintln!("graph_node_count:          {}", self.node_count);
+        eprintln!("graph_edge_count:          {}", self.edge_count);
+        eprintln!("graph_cached_result_count: {}", self.cached_result_count);
+        eprintln!("graph_evicted_node_count:  {}", self.evicted_node_count);
+        eprintln!("graph_eviction_count:      {}", self.eviction_count);
+        eprintln!("graph_approx_kilobytes:    {}", self.approx_bytes / 1024);
+    }
+}
+
+impl Balancer {
+    /// 頂点が使われたことを記録する
+    pub fn touch(&mut self, hash: u128) {
+        self.access_clock += 1;
+        self.last_used.insert(hash, self.access_clock);
+    }
+
+    ///
+    /// 頂点数が上限を超えていたら、最後に使われたのが古い頂点から削除する
+    ///
+    /// グループの構成が変わると、古い構成の集合が比較されることはほぼないので、
+    /// 最近使われていない頂点を消しても推論への影響は小さい
+    /// アイテム単体の頂点はアイテム同士の大小関係の推論に使うので消さない
+    ///
+    pub fn evict_stale_nodes(&mut self) {
+        if self.set_index.len() <= MAX_NODE_COUNT {
+            return;
+        }
+        let mut nodes: Vec<(usize, u128)> = self
+            .set_index
+            .nodes()
+            .filter(|v| v.count_ones() > 1)
+            .map(|v| (self.last_used.get(&v).copied().unwrap_or(0), v))
+            .collect();
+        nodes.sort_unstable();
+        let evict_count = (self.set_index.len() - EVICT_TARGET_NODE_COUNT).min(nodes.len());
+        let evicted: FastHashSet<u128> = nodes[..evict_count].iter().map(|(_, v)| *v).collect();
+
+        // 消す頂点の辺を消し、隣の頂点の隣接リストは最後に1回ずつまとめて絞り込む
+        let mut neighbors = FastHashSet::default();
+        for v in evicted.iter() {
+            if let Some(heavier) = self.left_edges.remove(v) {
+                for u in heavier {
+                    self.edge_kinds.remove(&(*v, u));
+                    neighbors.insert(u);
+                }
+            }
+            if let Some(lighter) = self.right_edges.remove(v) {
+                for u in lighter {
+                    self.edge_kinds.remove(&(u, *v));
+                    neighbors.insert(u);
+                }
+            }
+            self.last_used.remove(v);
+        }
+        for u in neighbors.iter() {
+            for edges in [&mut self.left_edges, &mut self.right_edges] {
+                let Some(u_edges) = edges.get_mut(u) else {
+                    continue;
+                };
+                u_edges.retain(|e| !evicted.contains(e));
+                if u_edges.is_empty() {
+                    edges.remove(u);
+                }
+            }
+        }
+        self.cached_results
+            .retain(|(l, r), _| !evicted.contains(l) && !evicted.contains(r));
+        self.set_index.retain(|v| !evicted.contains(&v));
+
+        self.evicted_node_count += evicted.len();
+        self.eviction_count += 1;
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let adjacency_count: usize = self
+            .left_edges
+            .values()
+            .chain(self.right_edges.values())
+            .map(|edges| edges.len())
+            .sum();
+        // ハッシュマップの1要素あたりのおおよその大きさ
+        let approx_bytes = adjacency_count * 16
+            + (self.left_edges.len() + self.right_edges.len()) * 40
+            + self.edge_kinds.len() * 40
+            + self.cached_results.len() * 40
+            + self.last_used.len() * 24
+            + self.set_index.len() * 16 * 4;
+        MemoryStats {
+            node_count: self.set_index.len(),
+            edge_count: self.edge_kinds.len(),
+            cached_result_count: self.cached_results.len(),
+            evicted_node_count: self.evicted_node_count,
+            eviction_count: self.eviction_count,
+            approx_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の乱数（xorshift）
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self, m: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % m
+        }
+
+        /// n個のアイテムから、2個以上選んだ集合
+        fn set(&mut self, n: usize) -> u128 {
+            loop {
+                let hash = (0..4).fold(0u128, |acc, _| acc | 1 << self.next(n as u64));
+                if hash.count_ones() >= 2 {
+                    return hash;
+                }
+            }
+        }
+    }
+
+    /// 辺と隣接リストとキャッシュが、残っている頂点だけで一致しているか
+    fn assert_consistent(balancer: &Balancer) {
+        let nodes: FastHashSet<u128> = balancer.set_index.nodes().collect();
+        for (&(lighter, heavier), _) in balancer.edge_kinds.iter() {
+            assert!(nodes.contains(&lighter) && nodes.contains(&heavier));
+            assert!(balancer.left_edges[&lighter].contains(&heavier));
+            assert!(balancer.right_edges[&heavier].contains(&lighter));
+        }
+        for (v, heavier) in balancer.left_edges.iter() {
+            assert!(!heavier.is_empty());
+            for u in heavier {
+                assert!(balancer.edge_kinds.contains_key(&(*v, *u)));
+            }
+        }
+        for (v, lighter) in balancer.right_edges.iter() {
+            assert!(!lighter.is_empty());
+            for u in lighter {
+                assert!(balancer.edge_kinds.contains_key(&(*u, *v)));
+            }
+        }
+        for (l, r) in balancer.cached_results.keys() {
+            assert!(nodes.contains(l) && nodes.contains(r));
+        }
+        for v in balancer.last_used.keys() {
+            assert!(nodes.contains(v));
+        }
+    }
+
+    #[test]
+    fn eviction_keeps_graph_consistent() {
+        let n = 60;
+        let mut rng = TestRng(31);
+        let mut balancer = Balancer::new();
+        // アイテム単体の頂点（最初に作るので、一番長く使われていない）
+        for i in 0..n - 1 {
+            balancer.insert_edge(1 << i, 1 << (i + 1), EdgeKind::Query);
+        }
+        let mut previous = rng.set(n);
+        while balancer.set_index.len() <= MAX_NODE_COUNT {
+            let hash = rng.set(n);
+            if hash == previous {
+                continue;
+            }
+            balancer.insert_edge(previous, hash, EdgeKind::Query);
+            balancer
+                .cached_results
+                .insert((previous, hash), BalanceResult::Left);
+            balancer
+                .cached_results
+                .insert((hash, previous), BalanceResult::Right);
+            previous = hash;
+        }
+        assert_consistent(&balancer);
+        // 最後に使った頂点は残る
+        let recent = previous;
+        balancer.touch(recent);
+
+        balancer.evict_stale_nodes();
+        assert_eq!(balancer.eviction_count, 1);
+        assert_eq!(balancer.set_index.len(), EVICT_TARGET_NODE_COUNT);
+        assert!(balancer.evicted_node_count > 0);
+        assert!(balancer.set_index.contains(recent));
+        // 一番古くても、アイテム単体の頂点とその間の辺は消さない
+        for i in 0..n - 1 {
+            assert!(balancer.set_index.contains(1 << i));
+            assert!(balancer.edge_kinds.contains_key(&(1 << i, 1 << (i + 1))));
+        }
+        assert_consistent(&balancer);
+
+        // 上限以下なら何もしない
+        balancer.evict_stale_nodes();
+        assert_eq!(balancer.eviction_count, 1);
+    }
+}
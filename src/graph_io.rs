@@ -0,0 +1,403 @@
+use crate::def::*;
+use crate::inference::*;
+use crate::json::*;
+use crate::util::*;
+
+fn hash_to_items(hash: u128) -> Vec<usize> {
+    (0..128).filter(|i| hash >> i & 1 == 1).collect()
+}
+
+fn result_to_str(result: BalanceResult) -> &'static str {
+    match result {
+        BalanceResult::Left => "<",
+        BalanceResult::Right => ">",
+        BalanceResult::Equal => "=",
+        BalanceResult::Unknown => "?",
+    }
+}
+
+fn str_to_result(s: &str) -> Option<BalanceResult> {
+    match s {
+        "<" => Some(BalanceResult::Left),
+        ">" => Some(BalanceResult::Right),
+        "=" => Some(BalanceResult::Equal),
+        "?" => Some(BalanceResult::Unknown),
+        _ => None,
+    }
+}
+
+fn kind_to_str(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::Query => "query",
+        EdgeKind::Inferred => "inferred",
+    }
+}
+
+fn str_to_kind(s: &str) -> Option<EdgeKind> {
+    match s {
+        "query" => Some(EdgeKind::Query),
+        "inferred" => Some(EdgeKind::Inferred),
+        _ => None,
+    }
+}
+
+impl Balancer {
+    /// グラフに現れる全ての集合を、ハッシュの昇順に並べて返す
+    fn collect_nodes(&self) -> Vec<u128> {
+        let mut nodes = FastHashSet::default();
+        for (v, edges) in self.left_edges.iter() {
+            nodes.insert(*v);
+            nodes.extend(edges.iter().copied());
+        }
+        for (v, edges) in self.right_edges.iter() {
+            nodes.insert(*v);
+            nodes.extend(edges.iter().copied());
+        }
+        for (left_hash, right_hash) in self.cached_results.keys().chain(self.comparisons.iter()) {
+            nodes.insert(*left_hash);
+            nodes.insert(*right_hash);
+        }
+        let mut nodes: Vec<u128> = nodes.into_iter().collect();
+        nodes.sort();
+        nodes
+    }
+
+    /// w(a) <= w(b)がわかっているアイテムの組(a, b)（a != b）
+    fn collect_item_order(&self) -> Vec<(usize, usize)> {
+        (0..128)
+            .flat_map(|a| {
+                iter_bits(self.item_order.heavier(a))
+                    .filter(move |b| *b != a)
+                    .map(move |b| (a, b))
+            })
+            .collect()
+    }
+
+    /// left <= right の辺を (left, right, kind) の形で、ハッシュの昇順に並べて返す
+    fn collect_edges(&self) -> Vec<(u128, u128, EdgeKind)> {
+        let mut edges = vec![];
+        for (left_hash, right_hashes) in self.left_edges.iter() {
+            for right_hash in right_hashes {
+                let kind = self
+                    .edge_kinds
+                    .get(&(*left_hash, *right_hash))
+                    .copied()
+                    .unwrap_or(EdgeKind::Inferred);
+                edges.push((*left_hash, *right_hash, kind));
+            }
+        }
+        edges.sort_by_key(|e| (e.0, e.1));
+        edges
+    }
+
+    ///
+    /// 比較グラフをJSONに変換する
+    ///
+    /// nodes: 各ノードのアイテム集合（添字がノードのid）
+    /// edges: from <= to の辺と、その辺が問い合わせ・推論のどちらで得られたか
+    /// cached_results: search_resultで得られてキャッシュされている比較結果
+    /// comparisons: 問い合わせで得た比較結果（lighter <= heavier、問い合わせた順）
+    /// item_order: アイテム単体同士で a <= b がわかっている組 [a, b]
+    ///
+    #[allow(unused)]
+    pub fn to_json(&self) -> String {
+        let nodes = self.collect_nodes();
+        let node_id: FastHashMap<u128, usize> =
+            nodes.iter().enumerate().map(|(i, v)| (*v, i)).collect();
+
+        let nodes_json = nodes
+            .iter()
+            .map(|v| {
+                JsonValue::Array(
+                    hash_to_items(*v)
+                        .into_iter()
+                        .map(|e| JsonValue::Number(e as f64))
+                        .collect(),
+                )
+            })
+            .collect();
+        let edges_json = self
+            .collect_edges()
+            .into_iter()
+            .map(|(left_hash, right_hash, kind)| {
+                JsonValue::Object(vec![
+                    (
+                        "from".to_owned(),
+                        JsonValue::Number(node_id[&left_hash] as f64),
+                    ),
+                    (
+                        "to".to_owned(),
+                        JsonValue::Number(node_id[&right_hash] as f64),
+                    ),
+                    (
+                        "kind".to_owned(),
+                        JsonValue::String(kind_to_str(kind).to_owned()),
+                    ),
+                ])
+            })
+            .collect();
+        let mut cached_results: Vec<_> = self.cached_results.iter().collect();
+        cached_results.sort_by_key(|(k, _)| **k);
+        let cached_results_json = cached_results
+            .into_iter()
+            .map(|((left_hash, right_hash), result)| {
+                JsonValue::Object(vec![
+                    (
+                        "left".to_owned(),
+                        JsonValue::Number(node_id[left_hash] as f64),
+                    ),
+                    (
+                        "right".to_owned(),
+                        JsonValue::Number(node_id[right_hash] as f64),
+                    ),
+                    (
+                        "result".to_owned(),
+                        JsonValue::String(result_to_str(*result).to_owned()),
+                    ),
+                ])
+            })
+            .collect();
+        let comparisons_json = self
+            .comparisons
+            .iter()
+            .map(|(lighter_hash, heavier_hash)| {
+                JsonValue::Object(vec![
+                    (
+                        "lighter".to_owned(),
+                        JsonValue::Number(node_id[lighter_hash] as f64),
+                    ),
+                    (
+                        "heavier".to_owned(),
+                        JsonValue::Number(node_id[heavier_hash] as f64),
+                    ),
+                ])
+            })
+            .collect();
+        let item_order_json = self
+            .collect_item_order()
+            .into_iter()
+            .map(|(a, b)| {
+                JsonValue::Array(vec![
+                    JsonValue::Number(a as f64),
+                    JsonValue::Number(b as f64),
+                ])
+            })
+            .collect();
+
+        JsonValue::Object(vec![
+            ("nodes".to_owned(), JsonValue::Array(nodes_json)),
+            ("edges".to_owned(), JsonValue::Array(edges_json)),
+            (
+                "cached_results".to_owned(),
+                JsonValue::Array(cached_results_json),
+            ),
+            ("comparisons".to_owned(), JsonValue::Array(comparisons_json)),
+            ("item_order".to_owned(), JsonValue::Array(item_order_json)),
+        ])
+        .to_json_string()
+    }
+
+    ///
+    /// 比較グラフをGraphvizのDOT形式に変換する
+    /// 辺は軽い方から重い方へ向き、問い合わせで得た辺は実線、推論で得た辺は破線で描く
+    ///
+    #[allow(unused)]
+    pub fn to_dot(&self) -> String {
+        let nodes = self.collect_nodes();
+        let node_id: FastHashMap<u128, usize> =
+            nodes.iter().enumerate().map(|(i, v)| (*v, i)).collect();
+
+        let mut s = String::from("digraph balancer {\n    rankdir=LR;\n    node [shape=box];\n");
+        for (i, v) in nodes.iter().enumerate() {
+            let items = hash_to_items(*v)
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            s += &format!("    n{} [label=\"{{{}}}\"];\n", i, items);
+        }
+        for (left_hash, right_hash, kind) in self.collect_edges() {
+            let style = match kind {
+                EdgeKind::Query => "solid",
+                EdgeKind::Inferred => "dashed",
+            };
+            s += &format!(
+                "    n{} -> n{} [style={}];\n",
+                node_id[&left_hash], node_id[&right_hash], style
+            );
+        }
+        s += "}\n";
+        s
+    }
+
+    ///
+    /// to_jsonで書き出したJSONからBalancerを復元する
+    /// comparisons, item_orderがない古い形式のJSONは、それらを空として読む
+    ///
+    #[allow(unused)]
+    pub fn from_json(s: &str) -> Result<Balancer, String> {
+        fn to_index(v: &JsonValue, key: &str, node_count: usize) -> Result<usize, String> {
+            let idx = v
+                .get(key)
+                .and_then(|e| e.as_usize())
+                .ok_or_else(|| format!("missing or invalid `{}`", key))?;
+            if idx >= node_count {
+                return Err(format!("`{}` out of range: {}", key, idx));
+            }
+            Ok(idx)
+        }
+
+        let json = parse(s)?;
+        let mut nodes = vec![];
+        for node in json
+            .get("nodes")
+            .and_then(|e| e.as_array())
+            .ok_or("missing `nodes`")?
+        {
+            let mut hash = 0u128;
+            for e in node.as_array().ok_or("node must be an array of items")? {
+                let item = e.as_usize().ok_or("item must be a non-negative integer")?;
+                if item >= 128 {
+                    return Err(format!("item out of range: {}", item));
+                }
+                hash |= 1 << item;
+            }
+            nodes.push(hash);
+        }
+
+        let mut balancer = Balancer::new();
+        for edge in json
+            .get("edges")
+            .and_then(|e| e.as_array())
+            .ok_or("missing `edges`")?
+        {
+            let from = to_index(edge, "from", nodes.len())?;
+            let to = to_index(edge, "to", nodes.len())?;
+            let kind = edge
+                .get("kind")
+                .and_then(|e| e.as_str())
+                .and_then(str_to_kind)
+                .ok_or("missing or invalid `kind`")?;
+            balancer.insert_edge(nodes[from], nodes[to], kind);
+        }
+        for cached_result in json
+            .get("cached_results")
+            .and_then(|e| e.as_array())
+            .ok_or("missing `cached_results`")?
+        {
+            let left = to_index(cached_result, "left", nodes.len())?;
+            let right = to_index(cached_result, "right", nodes.len())?;
+            let result = cached_result
+                .get("result")
+                .and_then(|e| e.as_str())
+                .and_then(str_to_result)
+                .ok_or("missing or invalid `result`")?;
+            balancer
+                .cached_results
+                .insert((nodes[left], nodes[right]), result);
+        }
+        let empty = vec![];
+        for comparison in json
+            .get("comparisons")
+            .map_or(Some(&empty), |e| e.as_array())
+            .ok_or("invalid `comparisons`")?
+        {
+            let lighter = to_index(comparison, "lighter", nodes.len())?;
+            let heavier = to_index(comparison, "heavier", nodes.len())?;
+            balancer.comparisons.push((nodes[lighter], nodes[heavier]));
+        }
+        for pair in json
+            .get("item_order")
+            .map_or(Some(&empty), |e| e.as_array())
+            .ok_or("invalid `item_order`")?
+        {
+            let items: Vec<usize> = pair
+                .as_array()
+                .map(|e| e.iter().filter_map(|e| e.as_usize()).collect())
+                .unwrap_or_default();
+            if items.len() != 2 || items.iter().any(|e| *e >= 128) {
+                return Err(format!("invalid item_order pair: {:?}", pair));
+            }
+            balancer.item_order.add(items[0], items[1]);
+        }
+        Ok(balancer)
+    }
+
+    /// `{path}.json`と`{path}.dot`に比較グラフを書き出す
+    #[allow(unused)]
+    pub fn write_graph(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(format!("{}.json", path), self.to_json())?;
+        std::fs::write(format!("{}.dot", path), self.to_dot())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_balancer() -> Balancer {
+        let mut balancer = Balancer::new();
+        // {0} <= {1}, {1} <= {2, 3}（問い合わせ）、{0} <= {2, 3}（推論）
+        balancer.insert_edge(1 << 0, 1 << 1, EdgeKind::Query);
+        balancer.insert_edge(1 << 1, 1 << 2 | 1 << 3, EdgeKind::Query);
+        balancer.insert_edge(1 << 0, 1 << 2 | 1 << 3, EdgeKind::Inferred);
+        balancer.comparisons.push((1 << 0, 1 << 1));
+        balancer.comparisons.push((1 << 1, 1 << 2 | 1 << 3));
+        // evictionでグラフから消えた集合も、comparisonsには残る
+        balancer.comparisons.push((1 << 4, 1 << 5 | 1 << 6));
+        balancer
+            .cached_results
+            .insert((1 << 0, 1 << 2 | 1 << 3), BalanceResult::Left);
+        balancer
+            .cached_results
+            .insert((1 << 2 | 1 << 3, 1 << 0), BalanceResult::Right);
+        // 重さが等しい可能性がある組（両向き）
+        balancer.item_order.add(5, 6);
+        balancer.item_order.add(6, 5);
+        balancer
+    }
+
+    #[test]
+    fn json_round_trip_restores_balancer() {
+        let balancer = sample_balancer();
+        let restored = Balancer::from_json(&balancer.to_json()).unwrap();
+
+        assert_eq!(restored.collect_edges(), balancer.collect_edges());
+        let mut cached_results: Vec<_> = balancer.cached_results.into_iter().collect();
+        let mut restored_cached_results: Vec<_> =
+            restored.cached_results.clone().into_iter().collect();
+        cached_results.sort_by_key(|e| e.0);
+        restored_cached_results.sort_by_key(|e| e.0);
+        assert_eq!(restored_cached_results, cached_results);
+        assert_eq!(restored.comparisons, balancer.comparisons);
+        for i in 0..128 {
+            assert_eq!(
+                restored.item_order.heavier(i),
+                balancer.item_order.heavier(i)
+            );
+            assert_eq!(
+                restored.item_order.lighter(i),
+                balancer.item_order.lighter(i)
+            );
+        }
+        // 書き出し直しても同じJSONになる
+        assert_eq!(
+            restored.to_json(),
+            Balancer::from_json(&restored.to_json()).unwrap().to_json()
+        );
+    }
+
+    #[test]
+    fn from_json_rejects_out_of_range_index() {
+        let json = r#"{"nodes": [[0]], "edges": [{"from": 0, "to": 1, "kind": "query"}], "cached_results": []}"#;
+        assert!(Balancer::from_json(json).is_err());
+    }
+
+    #[test]
+    fn from_json_accepts_json_without_comparisons() {
+        let json = r#"{"nodes": [[0], [1]], "edges": [{"from": 0, "to": 1, "kind": "query"}], "cached_results": []}"#;
+        let balancer = Balancer::from_json(json).unwrap();
+        assert!(balancer.comparisons.is_empty());
+        assert_eq!(balancer.search_result(1 << 0, 1 << 1), BalanceResult::Left);
+    }
+}
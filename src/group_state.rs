@@ -0,0 +1,276 @@
+use std::ops::Deref;
+
+/// どのグループにも入っていないアイテムのグループ番号
+const UNASSIGNED: usize = usize::MAX;
+
+///
+/// グループへのアイテムの割り当て
+///
+/// - アイテムからグループと、グループ内の位置を引けるので、取り出し・追加はO(1)
+/// - begin〜commitの間の変更は記録され、rollbackで元に戻せる
+/// - 取り出したアイテムは、どのグループにも入っていない状態になる（比較のために一時的に取り出す時に使う）
+/// - デバッグビルドでは、変更のたびに整合性を確認する
+///
+/// 読み出しは&[Vec<usize>]として行う（Deref）
+///
+pub struct GroupState {
+    groups: Vec<Vec<usize>>,
+    group_of: Vec<usize>,       // group_of[item]: アイテムが入っているグループ
+    index_in_group: Vec<usize>, // index_in_group[item]: groups[group_of[item]]の中での位置
+    undo_log: Vec<(usize, usize)>, // (アイテム, 変更前のグループ)
+    in_transaction: bool,
+}
+
+impl Deref for GroupState {
+    type Target = [Vec<usize>];
+
+    fn deref(&self) -> &[Vec<usize>] {
+        &self.groups
+    }
+}
+
+impl GroupState {
+    pub fn new(n: usize, groups: Vec<Vec<usize>>) -> GroupState {
+        let mut group_of = vec![UNASSIGNED; n];
+        let mut index_in_group = vec![0; n];
+        for (g_idx, group) in groups.iter().enumerate() {
+            for (i, item) in group.iter().enumerate() {
+                group_of[*item] = g_idx;
+                index_in_group[*item] = i;
+            }
+        }
+        let state = GroupState {
+            groups,
+            group_of,
+            index_in_group,
+            undo_log: vec![],
+            in_transaction: false,
+        };
+        state.check_invariants();
+        state
+    }
+
+    pub fn group_of(&self, item: usize) -> usize {
+        self.group_of[item]
+    }
+
+    /// アイテムをグループから取り出す
+    pub fn remove(&mut self, item: usize) {
+        let g_idx = self.group_of[item];
+        assert_ne!(g_idx, UNASSIGNED, "item {} is not in any group", item);
+        self.record(item, g_idx);
+        self.detach(item);
+        self.check_invariants();
+    }
+
+    /// どのグループにも入っていないアイテムをグループに入れる
+    pub fn insert(&mut self, item: usize, g_idx: usize) {
+        assert_eq!(
+            self.group_of[item], UNASSIGNED,
+            "item {} is already in a group",
+            item
+        );
+        self.record(item, UNASSIGNED);
+        self.attach(item, g_idx);
+        self.check_invariants();
+    }
+
+    /// アイテムを別のグループに移す
+    pub fn move_item(&mut self, item: usize, to_g_idx: usize) {
+        self.remove(item);
+        self.insert(item, to_g_idx);
+    }
+
+    /// 2つのアイテムのグループを入れ替える
+    pub fn swap_items(&mut self, item_a: usize, item_b: usize) {
+        let (g_a, g_b) = (self.group_of[item_a], self.group_of[item_b]);
+        self.move_item(item_a, g_b);
+        self.move_item(item_b, g_a);
+    }
+
+    /// 変更の記録を始める
+    pub fn begin(&mut self) {
+        assert!(!self.in_transaction, "transaction is already started");
+        self.in_transaction = true;
+        self.undo_log.clear();
+    }
+
+    /// 変更を確定する。取り出したままのアイテムがあってはいけない
+    pub fn commit(&mut self) {
+        assert!(self.in_transaction, "transaction is not started");
+        debug_assert!(
+            self.group_of.iter().all(|g_idx| *g_idx != UNASSIGNED),
+            "some items are not in any group at commit"
+        );
+        self.in_transaction = false;
+        self.undo_log.clear();
+    }
+
+    /// beginからの変更を全て元に戻す
+    /// グループ内のアイテムの順番は元に戻らない
+    pub fn rollback(&mut self) {
+        assert!(self.in_transaction, "transaction is not started");
+        while let Some((item, prev_g_idx)) = self.undo_log.pop() {
+            if self.group_of[item] != UNASSIGNED {
+                self.detach(item);
+            }
+            if prev_g_idx != UNASSIGNED {
+                self.attach(item, prev_g_idx);
+            }
+        }
+        self.in_transaction = false;
+        self.check_invariants();
+    }
+
+    /// エラーならbeginからの変更を全て元に戻し、結果をそのまま返す（問い合わせの失敗を呼び出し側に返す時に使う）
+    pub fn rollback_on_err<T, E>(&mut self, result: Result<T, E>) -> Result<T, E> {
+        if result.is_err() {
+            self.rollback();
+        }
+        result
+    }
+
+    fn record(&mut self, item: usize, prev_g_idx: usize) {
+        if self.in_transaction {
+            self.undo_log.push((item, prev_g_idx));
+        }
+    }
+
+    fn detach(&mut self, item: usize) {
+        let g_idx = self.group_of[item];
+        let i = self.index_in_group[item];
+        self.groups[g_idx].swap_remove(i);
+        if let Some(moved) = self.groups[g_idx].get(i) {
+            self.index_in_group[*moved] = i;
+        }
+        self.group_of[item] = UNASSIGNED;
+    }
+
+    fn attach(&mut self, item: usize, g_idx: usize) {
+        self.group_of[item] = g_idx;
+        self.index_in_group[item] = self.groups[g_idx].len();
+        self.groups[g_idx].push(item);
+    }
+
+    /// アイテムとグループの対応が一致しているかを確認する（デバッグビルドのみ）
+    fn check_invariants(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let mut count = 0;
+        for (g_idx, group) in self.groups.iter().enumerate() {
+            for (i, item) in group.iter().enumerate() {
+                debug_assert_eq!(self.group_of[*item], g_idx);
+                debug_assert_eq!(self.index_in_group[*item], i);
+            }
+            count += group.len();
+        }
+        let assigned_count = self
+            .group_of
+            .iter()
+            .filter(|g_idx| **g_idx != UNASSIGNED)
+            .count();
+        debug_assert_eq!(count, assigned_count);
+        debug_assert!(self.in_transaction || assigned_count == self.group_of.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_state() -> GroupState {
+        GroupState::new(7, vec![vec![0, 1, 2], vec![3, 4], vec![5, 6]])
+    }
+
+    /// グループ内の順番によらずに比べるため、各グループをソートして返す
+    fn sorted_groups(state: &GroupState) -> Vec<Vec<usize>> {
+        state
+            .iter()
+            .map(|group| {
+                let mut group = group.clone();
+                group.sort();
+                group
+            })
+            .collect()
+    }
+
+    /// 最初の割り当てに戻っているか（グループとアイテムからグループへの対応の両方）
+    fn assert_initial(state: &GroupState) {
+        assert_eq!(
+            sorted_groups(state),
+            vec![vec![0, 1, 2], vec![3, 4], vec![5, 6]]
+        );
+        let expected_group_of = [0, 0, 0, 1, 1, 2, 2];
+        for (item, g_idx) in expected_group_of.iter().enumerate() {
+            assert_eq!(state.group_of(item), *g_idx);
+        }
+    }
+
+    #[test]
+    fn rollback_restores_move() {
+        let mut state = new_state();
+        state.begin();
+        state.move_item(1, 2);
+        assert_eq!(state.group_of(1), 2);
+        assert_eq!(sorted_groups(&state)[2], vec![1, 5, 6]);
+        state.rollback();
+        assert_initial(&state);
+    }
+
+    #[test]
+    fn rollback_restores_swap() {
+        let mut state = new_state();
+        state.begin();
+        state.swap_items(0, 4);
+        assert_eq!((state.group_of(0), state.group_of(4)), (1, 0));
+        state.rollback();
+        assert_initial(&state);
+    }
+
+    #[test]
+    fn rollback_restores_multi_item_exchange() {
+        let mut state = new_state();
+        state.begin();
+        // 比べるために取り出してから、入れ替える（exchange_itemsと同じ順番）
+        for item in [0, 2, 3] {
+            state.remove(item);
+        }
+        state.insert(0, 1);
+        state.insert(2, 1);
+        state.insert(3, 0);
+        state.move_item(6, 0);
+        assert_eq!(
+            sorted_groups(&state),
+            vec![vec![1, 3, 6], vec![0, 2, 4], vec![5]]
+        );
+        state.rollback();
+        assert_initial(&state);
+    }
+
+    #[test]
+    fn rollback_restores_removed_items() {
+        let mut state = new_state();
+        state.begin();
+        state.remove(5);
+        state.remove(3);
+        state.rollback();
+        assert_initial(&state);
+    }
+
+    #[test]
+    fn commit_keeps_changes_and_next_rollback_starts_from_them() {
+        let mut state = new_state();
+        state.begin();
+        state.swap_items(2, 5);
+        state.commit();
+        state.begin();
+        state.move_item(3, 2);
+        state.rollback();
+        assert_eq!(
+            sorted_groups(&state),
+            vec![vec![0, 1, 5], vec![3, 4], vec![2, 6]]
+        );
+        assert_eq!((state.group_of(2), state.group_of(5)), (2, 0));
+    }
+}
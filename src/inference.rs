@@ -0,0 +1,330 @@
+use crate::def::*;
+
+/// infer_relationで推論に使う差分の最大の個数
+pub const MAX_INFER_DIFF: u32 = 2; // :param
+
+///
+/// アイテム単体同士の大小関係
+/// w(a) <= w(b)がわかっている組を、推移閉包を取った状態で保持する
+///
+pub struct ItemOrder {
+    heavier: Vec<u128>, // heavier[a]: w(a) <= w(b)がわかっているbの集合
+    lighter: Vec<u128>, // lighter[b]: w(a) <= w(b)がわかっているaの集合
+}
+
+impl ItemOrder {
+    pub fn new() -> ItemOrder {
+        ItemOrder {
+            heavier: vec![0; 128],
+            lighter: vec![0; 128],
+        }
+    }
+
+    /// w(a) <= w(b)を追加する
+    pub fn add(&mut self, a: usize, b: usize) {
+        if a == b || self.heavier[a] >> b & 1 == 1 {
+            return;
+        }
+        // aより軽いもの全てに対して、bより重いもの全てが重いことがわかる
+        let lighter_set = self.lighter[a] | 1 << a;
+        let heavier_set = self.heavier[b] | 1 << b;
+        for x in iter_bits(lighter_set) {
+            self.heavier[x] |= heavier_set;
+        }
+        for y in iter_bits(heavier_set) {
+            self.lighter[y] |= lighter_set;
+        }
+    }
+
+    /// w(a) <= w(b)がわかっているbの集合
+    pub fn heavier(&self, a: usize) -> u128 {
+        self.heavier[a]
+    }
+
+    /// w(b) <= w(a)がわかっているbの集合
+    pub fn lighter(&self, a: usize) -> u128 {
+        self.lighter[a]
+    }
+
+    /// w(b) < w(a)がわかっているbの集合（重さが等しい可能性があるものは除く）
+    fn strictly_lighter(&self, a: usize) -> u128 {
+        self.lighter[a] & !self.heavier[a] & !(1 << a)
+    }
+
+    ///
+    /// group_hashのアイテムのうち、それより軽いことがわかっているアイテムがgroup_hashにないものの集合
+    /// 一番軽いアイテムは必ずこの中にあり、1個だけなら一番軽いことが確定する
+    ///
+    pub fn lightest_candidates(&self, group_hash: u128) -> u128 {
+        iter_bits(group_hash)
+            .filter(|a| self.strictly_lighter(*a) & group_hash == 0)
+            .fold(0, |acc, a| acc | 1 << a)
+    }
+}
+
+pub fn iter_bits(mut hash: u128) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if hash == 0 {
+            return None;
+        }
+        let i = hash.trailing_zeros() as usize;
+        hash &= hash - 1;
+        Some(i)
+    })
+}
+
+///
+/// left_hashの各アイテムaに、w(a) <= w(b)がわかっているright_hashのアイテムbを重複なく対応させる
+/// 対応づけられたアイテムの集合(left側, right側)を返す
+///
+/// 二部グラフの最大マッチングを増加路で求める
+///
+pub fn dominance_matching(
+    item_order: &ItemOrder,
+    left_hash: u128,
+    right_hash: u128,
+) -> (u128, u128) {
+    fn augment(
+        a: usize,
+        item_order: &ItemOrder,
+        right_hash: u128,
+        matched_to: &mut [usize],
+        visited: &mut u128,
+    ) -> bool {
+        for b in iter_bits(item_order.heavier(a) & right_hash & !*visited) {
+            *visited |= 1 << b;
+            if matched_to[b] == usize::MAX
+                || augment(matched_to[b], item_order, right_hash, matched_to, visited)
+            {
+                matched_to[b] = a;
+                return true;
+            }
+        }
+        false
+    }
+
+    let mut matched_to = [usize::MAX; 128];
+    let mut matched_left = 0;
+    for a in iter_bits(left_hash) {
+        let mut visited = 0;
+        if augment(a, item_order, right_hash, &mut matched_to, &mut visited) {
+            matched_left |= 1 << a;
+        }
+    }
+    let matched_right = iter_bits(right_hash)
+        .filter(|b| matched_to[*b] != usize::MAX)
+        .fold(0, |acc, b| acc | 1 << b);
+    (matched_left, matched_right)
+}
+
+impl Balancer {
+    ///
+    /// 既存の比較結果から、v_hashとu_hashの大小関係を推論する
+    /// 推論できた場合は(軽い方, 重い方)を返す
+    ///
+    /// 共通部分をC、v = C + A、u = C + Bとすると、w(A) <= w(B)ならw(v) <= w(u)である
+    /// 1. Aが空（v ⊂ u）ならw(v) <= w(u)
+    /// 2. Aの各アイテムを、それ以上の重さであることがわかっているBのアイテムに重複なく対応させる
+    ///     - 全て対応できればw(A) <= w(B)（残ったBのアイテムは重さが正なので足しても大小関係は変わらない）
+    ///     - 対応できなかった残りをA'、B'として、w(A') <= w(B')がわかっていればw(A) <= w(B)
+    ///       （グラフの探索は重いので、A、Bが1個ずつの場合に限る）
+    ///
+    pub fn infer_relation(&self, v_hash: u128, u_hash: u128) -> Option<(u128, u128)> {
+        let common = v_hash & u_hash;
+        let (a, b) = (v_hash ^ common, u_hash ^ common);
+        if a == 0 {
+            return Some((v_hash, u_hash));
+        }
+        if b == 0 {
+            return Some((u_hash, v_hash));
+        }
+        if a.count_ones() > MAX_INFER_DIFF || b.count_ones() > MAX_INFER_DIFF {
+            return None;
+        }
+        if self.is_dominated(a, b) {
+            return Some((v_hash, u_hash));
+        }
+        if self.is_dominated(b, a) {
+            return Some((u_hash, v_hash));
+        }
+        None
+    }
+
+    /// w(a) <= w(b)が示せるか
+    fn is_dominated(&self, a: u128, b: u128) -> bool {
+        let (matched_a, matched_b) = dominance_matching(&self.item_order, a, b);
+        let (rest_a, rest_b) = (a ^ matched_a, b ^ matched_b);
+        if rest_a == 0 {
+            return true;
+        }
+        if rest_b == 0 {
+            return false;
+        }
+        match self.cached_results.get(&(rest_a, rest_b)) {
+            Some(BalanceResult::Left) | Some(BalanceResult::Equal) => return true,
+            Some(_) => return false,
+            None => {}
+        }
+        if self
+            .left_edges
+            .get(&rest_a)
+            .is_some_and(|edges| edges.contains(&rest_b))
+        {
+            return true;
+        }
+        if a.count_ones() > 1 || b.count_ones() > 1 {
+            return false;
+        }
+        matches!(
+            self.search_result(rest_a, rest_b),
+            BalanceResult::Left | BalanceResult::Equal
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_hash(items: &[usize]) -> u128 {
+        items.iter().fold(0, |acc, i| acc | 1 << i)
+    }
+
+    fn balancer_with_order(pairs: &[(usize, usize)]) -> Balancer {
+        let mut balancer = Balancer::new();
+        for &(a, b) in pairs {
+            balancer.item_order.add(a, b);
+        }
+        balancer
+    }
+
+    #[test]
+    fn item_order_add_keeps_transitive_closure() {
+        let mut order = ItemOrder::new();
+        // 繋がる順と逆に追加しても推移閉包になる
+        order.add(2, 3);
+        order.add(1, 2);
+        order.add(0, 1);
+        assert_eq!(order.heavier(0), to_hash(&[1, 2, 3]));
+        assert_eq!(order.heavier(1), to_hash(&[2, 3]));
+        assert_eq!(order.lighter(3), to_hash(&[0, 1, 2]));
+        assert_eq!(order.heavier(3), 0);
+
+        // 別の鎖をつなぐと、両方の鎖の全ての組がわかる
+        order.add(4, 5);
+        order.add(3, 4);
+        assert_eq!(order.heavier(0), to_hash(&[1, 2, 3, 4, 5]));
+        assert_eq!(order.lighter(5), to_hash(&[0, 1, 2, 3, 4]));
+
+        // 循環すると重さが等しい可能性があり、strictly_lighterからは除かれる
+        order.add(5, 0);
+        assert_eq!(order.strictly_lighter(5), 0);
+        assert_eq!(
+            order.lightest_candidates(to_hash(&[0, 5])),
+            to_hash(&[0, 5])
+        );
+    }
+
+    #[test]
+    fn dominance_matching_matches_all_items() {
+        // 0 <= 2, 0 <= 3, 1 <= 2: 0 -> 2を先に選ぶと1が対応できないので、増加路で0 -> 3に付け替える
+        let balancer = balancer_with_order(&[(0, 2), (0, 3), (1, 2)]);
+        let (matched_left, matched_right) =
+            dominance_matching(&balancer.item_order, to_hash(&[0, 1]), to_hash(&[2, 3]));
+        assert_eq!(matched_left, to_hash(&[0, 1]));
+        assert_eq!(matched_right, to_hash(&[2, 3]));
+    }
+
+    #[test]
+    fn dominance_matching_reports_unmatched_items() {
+        // 0 <= 2, 1 <= 2: 2に対応できるのは1個だけで、3より軽いことがわかっているものはない
+        let balancer = balancer_with_order(&[(0, 2), (1, 2)]);
+        let (matched_left, matched_right) =
+            dominance_matching(&balancer.item_order, to_hash(&[0, 1]), to_hash(&[2, 3]));
+        assert_eq!(matched_left.count_ones(), 1);
+        assert_eq!(matched_right, to_hash(&[2]));
+    }
+
+    #[test]
+    fn infer_relation_derives_order_from_matching() {
+        let balancer = balancer_with_order(&[(0, 2), (1, 3)]);
+        let (v, u) = (to_hash(&[0, 1, 4]), to_hash(&[2, 3, 4]));
+        assert_eq!(balancer.infer_relation(v, u), Some((v, u)));
+        // 引数の順番によらず(軽い方, 重い方)を返す
+        assert_eq!(balancer.infer_relation(u, v), Some((v, u)));
+        // 部分集合は軽い
+        let w = to_hash(&[2, 3]);
+        assert_eq!(balancer.infer_relation(u, w), Some((w, u)));
+    }
+
+    #[test]
+    fn infer_relation_uses_known_relation_of_unmatched_rest() {
+        // 0 <= 2が対応し、残りの{1} <= {3}はキャッシュされた比較結果からわかる
+        let mut balancer = balancer_with_order(&[(0, 2)]);
+        balancer
+            .cached_results
+            .insert((1 << 1, 1 << 3), BalanceResult::Left);
+        let (v, u) = (to_hash(&[0, 1]), to_hash(&[2, 3]));
+        assert_eq!(balancer.infer_relation(v, u), Some((v, u)));
+    }
+
+    #[test]
+    fn infer_relation_returns_none_for_incomparable_sets() {
+        // 0 <= 2だけでは、1と3の大小がわからない
+        let balancer = balancer_with_order(&[(0, 2)]);
+        assert_eq!(
+            balancer.infer_relation(to_hash(&[0, 1]), to_hash(&[2, 3])),
+            None
+        );
+        // 大小関係が何もわかっていない
+        let balancer = balancer_with_order(&[]);
+        assert_eq!(balancer.infer_relation(1 << 0, 1 << 1), None);
+        // 対応が逆向き（0 <= 2, 3 <= 1）では、どちらとも言えない
+        let balancer = balancer_with_order(&[(0, 2), (3, 1)]);
+        assert_eq!(
+            balancer.infer_relation(to_hash(&[0, 1]), to_hash(&[2, 3])),
+            None
+        );
+    }
+
+    #[test]
+    fn infer_relation_is_sound_for_random_weights() {
+        // 乱数の重さで正しいアイテム同士の関係だけを与え、推論結果が真の重さと矛盾しないことを確かめる
+        let mut seed: u64 = 12345;
+        let mut next = move |m: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % m
+        };
+        for _ in 0..200 {
+            let n = 10;
+            let weights: Vec<u64> = (0..n).map(|_| 1 + next(20)).collect();
+            let weight = |hash: u128| iter_bits(hash).map(|i| weights[i]).sum::<u64>();
+            let mut balancer = Balancer::new();
+            for _ in 0..next(15) {
+                let (a, b) = (next(n as u64) as usize, next(n as u64) as usize);
+                if weights[a] <= weights[b] {
+                    balancer.item_order.add(a, b);
+                } else {
+                    balancer.item_order.add(b, a);
+                }
+            }
+            for _ in 0..50 {
+                let (v, u) = (next(1 << n) as u128, next(1 << n) as u128);
+                if v == 0 || u == 0 {
+                    continue;
+                }
+                if let Some((lighter, heavier)) = balancer.infer_relation(v, u) {
+                    assert!(
+                        weight(lighter) <= weight(heavier),
+                        "inferred {:b} <= {:b} with weights {:?}",
+                        lighter,
+                        heavier,
+                        weights
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,64 +1,205 @@
 use crate::def::*;
-use std::io::{Stdin, Write};
+use std::io::{BufRead, Stdin, Write};
 
-use proconio::*;
+///
+/// 問い合わせの失敗
+///
+/// - InvalidQuery, Overlapping: 呼び出し側の誤り（ジャッジに送るとWAになる）
+/// - Protocol, Io: ジャッジとのやり取りの失敗（続けられない）
+/// - BudgetExhausted: クエリを使い切った（探索を打ち切る合図として使う）
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    InvalidQuery(String),
+    Overlapping(usize), // 両側に含まれるアイテム
+    Protocol(String),
+    BudgetExhausted,
+    Io(String),
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::InvalidQuery(reason) => write!(f, "invalid query: {}", reason),
+            QueryError::Overlapping(item) => write!(f, "item {} is on both sides", item),
+            QueryError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            QueryError::BudgetExhausted => write!(f, "query budget exhausted"),
+            QueryError::Io(reason) => write!(f, "I/O error: {}", reason),
+        }
+    }
+}
+
+///
+/// ジャッジに送れることを確認した問い合わせ
+/// 両側が空でなく、アイテムが0..Nの範囲にあり、同じアイテムが2回以上出てこない
+///
+#[derive(Debug, Clone)]
+pub struct Query {
+    left_v: Vec<usize>,
+    right_v: Vec<usize>,
+}
+
+impl Query {
+    pub fn new(left_v: &[usize], right_v: &[usize], n: usize) -> Result<Query, QueryError> {
+        if left_v.is_empty() || right_v.is_empty() {
+            return Err(QueryError::InvalidQuery(format!(
+                "empty side ({} vs {} items)",
+                left_v.len(),
+                right_v.len()
+            )));
+        }
+        // 0: どちらにもない、1: 左、2: 右
+        let mut side = vec![0; n];
+        for (v, s) in [(left_v, 1), (right_v, 2)] {
+            for &e in v.iter() {
+                if e >= n {
+                    return Err(QueryError::InvalidQuery(format!(
+                        "item {} is out of range 0..{}",
+                        e, n
+                    )));
+                }
+                if side[e] == s {
+                    return Err(QueryError::InvalidQuery(format!(
+                        "item {} appears twice",
+                        e
+                    )));
+                }
+                if side[e] != 0 {
+                    return Err(QueryError::Overlapping(e));
+                }
+                side[e] = s;
+            }
+        }
+        Ok(Query {
+            left_v: left_v.to_vec(),
+            right_v: right_v.to_vec(),
+        })
+    }
+}
 
 pub struct Interactor {
-    source: proconio::source::line::LineSource<std::io::BufReader<Stdin>>,
+    source: std::io::BufReader<Stdin>,
     pub query_count: usize,
     max_query_count: usize,
+    n: usize,
+    fatal_error: Option<QueryError>, // ジャッジとのやり取りに失敗した後は、問い合わせずにこのエラーを返す
+    #[cfg(test)]
+    judge: Option<Box<Judge>>, // 標準入出力の代わりに応答を返す
 }
 
+/// 問い合わせの両側を受け取り、ジャッジの代わりに応答を返す（テスト用）
+#[cfg(test)]
+type Judge = dyn FnMut(&[usize], &[usize]) -> Result<BalanceResult, QueryError>;
+
 impl Interactor {
     pub fn new() -> Interactor {
         Interactor {
-            source: proconio::source::line::LineSource::new(std::io::BufReader::new(
-                std::io::stdin(),
-            )),
+            source: std::io::BufReader::new(std::io::stdin()),
             query_count: 0,
             max_query_count: 1,
+            n: 0,
+            fatal_error: None,
+            #[cfg(test)]
+            judge: None,
         }
     }
 
-    pub fn read_input(&mut self) -> Input {
-        input! {
-            from &mut self.source,
-            n: usize,
-            d: usize,
-            q: usize
-        }
+    /// 標準入出力の代わりに、judgeが応答を返す（テスト用）
+    #[cfg(test)]
+    pub fn with_judge(
+        input: &Input,
+        judge: impl FnMut(&[usize], &[usize]) -> Result<BalanceResult, QueryError> + 'static,
+    ) -> Interactor {
+        let mut interactor = Interactor::new();
+        interactor.max_query_count = input.q;
+        interactor.n = input.n;
+        interactor.judge = Some(Box::new(judge));
+        interactor
+    }
+
+    /// 真の重さweightsで応答する（テスト用）
+    #[cfg(test)]
+    pub fn with_weights(input: &Input, weights: Vec<i64>) -> Interactor {
+        Interactor::with_judge(input, move |left_v, right_v| {
+            let weight = |v: &[usize]| v.iter().map(|i| weights[*i]).sum::<i64>();
+            Ok(match weight(left_v).cmp(&weight(right_v)) {
+                std::cmp::Ordering::Less => BalanceResult::Left,
+                std::cmp::Ordering::Greater => BalanceResult::Right,
+                std::cmp::Ordering::Equal => BalanceResult::Equal,
+            })
+        })
+    }
+
+    /// 1行目のN D Qを読む。読めなければIo、形式が違えばProtocolを返す
+    pub fn read_input(&mut self) -> Result<Input, QueryError> {
+        let line = self.read_line()?;
+        let v = line
+            .split_whitespace()
+            .map(|e| {
+                e.parse()
+                    .map_err(|_| QueryError::Protocol(format!("invalid number {:?} in input", e)))
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        let [n, d, q] = v[..] else {
+            return Err(QueryError::Protocol(format!(
+                "expected N D Q, got {:?}",
+                line.trim()
+            )));
+        };
         self.max_query_count = q;
-        Input { n, d, q }
+        self.n = n;
+        Ok(Input { n, d, q })
     }
 
-    pub fn output_query(&mut self, left_v: &Vec<usize>, right_v: &Vec<usize>) -> BalanceResult {
+    /// 確認してから問い合わせる
+    pub fn output_query(
+        &mut self,
+        left_v: &[usize],
+        right_v: &[usize],
+    ) -> Result<BalanceResult, QueryError> {
+        let query = Query::new(left_v, right_v, self.n)?;
+        self.query(&query)
+    }
+
+    pub fn query(&mut self, query: &Query) -> Result<BalanceResult, QueryError> {
+        if let Some(e) = self.fatal_error.as_ref() {
+            return Err(e.clone());
+        }
         if self.query_count >= self.max_query_count {
-            // eprintln!("exceed query_count limit");
-            return BalanceResult::Unknown;
+            return Err(QueryError::BudgetExhausted);
         }
         self.query_count += 1;
-        print!("{} {} ", left_v.len(), right_v.len());
-        for e in left_v.iter() {
-            print!("{} ", e);
+        let result = self.exchange(query);
+        if let Err(e @ (QueryError::Protocol(_) | QueryError::Io(_))) = result.as_ref() {
+            self.fatal_error = Some(e.clone());
         }
-        for e in right_v.iter() {
-            print!("{} ", e);
+        result
+    }
+
+    /// 問い合わせを書き、ジャッジの応答を読む
+    fn exchange(&mut self, query: &Query) -> Result<BalanceResult, QueryError> {
+        #[cfg(test)]
+        if let Some(judge) = self.judge.as_mut() {
+            return judge(&query.left_v, &query.right_v);
         }
-        println!();
-        self.flush();
-        input! {
-            from &mut self.source,
-            s: String
+        let mut line = format!("{} {} ", query.left_v.len(), query.right_v.len());
+        for e in query.left_v.iter().chain(query.right_v.iter()) {
+            line += &format!("{} ", e);
         }
-        match s.as_str() {
-            "<" => BalanceResult::Left,
-            ">" => BalanceResult::Right,
-            "=" => BalanceResult::Equal,
-            _ => panic!("failed to read_result: {}", s),
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", line)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| QueryError::Io(e.to_string()))?;
+        let response = self.read_line()?;
+        match response.trim() {
+            "<" => Ok(BalanceResult::Left),
+            ">" => Ok(BalanceResult::Right),
+            "=" => Ok(BalanceResult::Equal),
+            s => Err(QueryError::Protocol(format!("unexpected response {:?}", s))),
         }
     }
 
-    pub fn output_d(&self, d: &Vec<usize>, for_debug: bool) {
+    pub fn output_d(&self, d: &[usize], for_debug: bool) {
         if for_debug {
             print!("#c ");
         }
@@ -69,7 +210,125 @@ impl Interactor {
         self.flush();
     }
 
+    fn read_line(&mut self) -> Result<String, QueryError> {
+        let mut line = String::new();
+        match self.source.read_line(&mut line) {
+            Ok(0) => Err(QueryError::Protocol("unexpected end of input".to_owned())),
+            Ok(_) => Ok(line),
+            Err(e) => Err(QueryError::Io(e.to_string())),
+        }
+    }
+
     fn flush(&self) {
         std::io::stdout().flush().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn query_rejects_invalid_sides() {
+        assert!(Query::new(&[0, 1], &[2], 3).is_ok());
+        // 片側が空
+        assert!(matches!(
+            Query::new(&[], &[1], 3),
+            Err(QueryError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            Query::new(&[0], &[], 3),
+            Err(QueryError::InvalidQuery(_))
+        ));
+        // 範囲外
+        assert!(matches!(
+            Query::new(&[0], &[3], 3),
+            Err(QueryError::InvalidQuery(reason)) if reason.contains("out of range")
+        ));
+        // 同じ側に2回
+        assert!(matches!(
+            Query::new(&[1, 1], &[2], 3),
+            Err(QueryError::InvalidQuery(reason)) if reason.contains("twice")
+        ));
+        // 両側に含まれる
+        assert_eq!(
+            Query::new(&[0, 2], &[1, 2], 3).err(),
+            Some(QueryError::Overlapping(2))
+        );
+    }
+
+    #[test]
+    fn invalid_query_is_not_sent() {
+        let input = Input { n: 3, d: 2, q: 10 };
+        let sent = Rc::new(Cell::new(0));
+        let sent_by_judge = sent.clone();
+        let mut interactor = Interactor::with_judge(&input, move |_, _| {
+            sent_by_judge.set(sent_by_judge.get() + 1);
+            Ok(BalanceResult::Left)
+        });
+        assert_eq!(
+            interactor.output_query(&[0], &[0]),
+            Err(QueryError::Overlapping(0))
+        );
+        assert_eq!(interactor.query_count, 0);
+        assert_eq!(sent.get(), 0);
+        // 呼び出し側の誤りの後も、続けて問い合わせられる
+        assert_eq!(interactor.output_query(&[0], &[1]), Ok(BalanceResult::Left));
+        assert_eq!(sent.get(), 1);
+    }
+
+    #[test]
+    fn budget_is_exhausted_after_q_queries() {
+        let input = Input { n: 2, d: 2, q: 2 };
+        let mut interactor = Interactor::with_weights(&input, vec![1, 2]);
+        assert_eq!(interactor.output_query(&[0], &[1]), Ok(BalanceResult::Left));
+        assert_eq!(
+            interactor.output_query(&[1], &[0]),
+            Ok(BalanceResult::Right)
+        );
+        assert_eq!(
+            interactor.output_query(&[0], &[1]),
+            Err(QueryError::BudgetExhausted)
+        );
+        assert_eq!(interactor.query_count, 2);
+    }
+
+    #[test]
+    fn protocol_error_is_fatal() {
+        let input = Input { n: 2, d: 2, q: 10 };
+        let sent = Rc::new(Cell::new(0));
+        let sent_by_judge = sent.clone();
+        let mut interactor = Interactor::with_judge(&input, move |_, _| {
+            sent_by_judge.set(sent_by_judge.get() + 1);
+            Err(QueryError::Protocol("unexpected response \"?\"".to_owned()))
+        });
+        let error = interactor.output_query(&[0], &[1]).unwrap_err();
+        assert!(matches!(error, QueryError::Protocol(_)));
+        // 以降はジャッジに送らずに同じエラーを返す
+        assert_eq!(interactor.output_query(&[0], &[1]), Err(error));
+        assert_eq!(sent.get(), 1);
+        assert_eq!(interactor.query_count, 1);
+    }
+
+    #[test]
+    fn query_error_reaches_caller_through_balancer() {
+        let input = Input { n: 4, d: 2, q: 1 };
+        let mut interactor = Interactor::with_weights(&input, vec![1, 2, 3, 4]);
+        let mut balancer = Balancer::new();
+        assert_eq!(
+            balancer.get_result(&[0], &[1], &mut interactor),
+            Ok(BalanceResult::Left)
+        );
+        // キャッシュでわかるものはクエリを使い切っても答えられる
+        assert_eq!(
+            balancer.get_result(&[1], &[0], &mut interactor),
+            Ok(BalanceResult::Right)
+        );
+        assert_eq!(
+            balancer.get_result(&[2], &[3], &mut interactor),
+            Err(QueryError::BudgetExhausted)
+        );
+    }
+}
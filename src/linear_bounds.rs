@@ -0,0 +1,649 @@
+use crate::def::*;
+use crate::inference::*;
+use crate::util::*;
+
+/// 線形計画で使う、最近の比較結果の数の上限（単体表の大きさを抑える）
+const LINEAR_MAX_CONSTRAINT_COUNT: usize = 200; // :param
+/// 線形計画に使う時間の合計の上限（秒）。経過時間に比例して使えるようにする
+const LINEAR_TIME_BUDGET: f64 = 0.2; // :param
+/// 重さの範囲を求めるのに使う時間の合計の上限（秒）。終盤にしか使わないので、LINEAR_TIME_BUDGETとは別にする
+const LINEAR_BOUNDS_TIME_BUDGET: f64 = 0.05; // :param
+/// 単体法の反復回数の上限
+const SIMPLEX_MAX_ITERATION: usize = 2000;
+/// 重さの割合の差がこれ以下なら0とみなす
+/// 重さは整数で、合計は5e8以下なので、割合の差が1e-9以下なら重さの差は1未満、つまり0以下
+const EPS: f64 = 1e-9;
+/// これ以下の係数では掃き出さない（丸め誤差で残った値で掃き出すと、単体表が壊れる）
+const PIVOT_EPS: f64 = 1e-7;
+
+#[derive(Debug, Default, Clone)]
+pub struct LinearStats {
+    pub solve_count: usize,
+    pub decided_count: usize, // グラフの探索ではわからず、線形計画でわかった回数
+    pub skipped_count: usize, // 同じ不等式で同じ組を解いたことがある、または解いてもわからないので解かなかった回数
+    pub screened_count: usize, // 重さの範囲から、問い合わせずに不採用にした候補の数
+    pub seconds: f64,
+    pub bounds_solve_count: usize,
+    pub bounds_seconds: f64,
+    undecided: FastHashSet<(u128, u128)>, // undecided_comparison_count個の比較結果で解いて、わからなかった組
+    undecided_comparison_count: usize,
+    item_bounds: FastHashMap<usize, Option<(f64, f64)>>, // bounds_comparison_count個の比較結果で求めた、アイテムの重さの割合の範囲
+    bounds_comparison_count: usize,
+}
+
+impl LinearStats {
+    pub fn print(&self) {
+        eprintln!("linear_solve_count:     {}", self.solve_count);
+        eprintln!("linear_decided_count:   {}", self.decided_count);
+        eprintln!("linear_skipped_count:   {}", self.skipped_count);
+        eprintln!("linear_screened_count:  {}", self.screened_count);
+        eprintln!("linear_seconds:         {:.3}", self.seconds);
+        eprintln!("linear_bounds_solve_count: {}", self.bounds_solve_count);
+        eprintln!("linear_bounds_seconds:  {:.3}", self.bounds_seconds);
+    }
+}
+
+///
+/// 比較結果の不等式 Σw(L) <= Σw(R) と、正規化 Σw <= 1、w >= 0 を満たす重さの範囲
+///
+/// 単体法（密な単体表、Blandの規則）で、集合の重さの線形和の最大値を求める
+/// 右辺が全て0以上なので、原点を初期解にでき、第1段階は要らない
+///
+/// - max(w(A) - w(B)) <= 0なら、w(A) <= w(B)が確か
+///   グラフの探索では、不等式を足し合わせて得られる関係（A + B <= C + D, C <= A ならB <= D など）を全ては見つけられない
+/// - 単体表は丸め誤差で狂うことがあるので、確かと言うのは、双対解から元の係数だけで上界を確かめられた時だけ
+///
+pub struct LinearBounds {
+    items: Vec<usize>,   // 変数に対応するアイテム
+    items_hash: u128,    // 不等式に出てくるアイテムの集合
+    var_of: Vec<usize>,  // var_of[item]: 変数の番号（不等式に出てこないアイテムはusize::MAX）
+    rows: Vec<Vec<f64>>, // 不等式の左辺の係数
+    rhs: Vec<f64>,       // 不等式の右辺
+}
+
+impl LinearBounds {
+    pub fn new(comparisons: &[(u128, u128)]) -> LinearBounds {
+        let all_hash = comparisons.iter().fold(0, |acc, (l, h)| acc | l | h);
+        let items: Vec<usize> = iter_bits(all_hash).collect();
+        let mut var_of = vec![usize::MAX; 128];
+        for (v, item) in items.iter().enumerate() {
+            var_of[*item] = v;
+        }
+        let mut rows = vec![];
+        let mut rhs = vec![];
+        for &(lighter_hash, heavier_hash) in comparisons.iter() {
+            let mut row = vec![0.; items.len()];
+            for i in iter_bits(lighter_hash) {
+                row[var_of[i]] += 1.;
+            }
+            for i in iter_bits(heavier_hash) {
+                row[var_of[i]] -= 1.;
+            }
+            rows.push(row);
+            rhs.push(0.);
+        }
+        rows.push(vec![1.; items.len()]);
+        rhs.push(1.);
+        LinearBounds {
+            items,
+            items_hash: all_hash,
+            var_of,
+            rows,
+            rhs,
+        }
+    }
+
+    fn objective(&self, positive_hash: u128, negative_hash: u128) -> Vec<f64> {
+        let mut c = vec![0.; self.items.len()];
+        for i in iter_bits(positive_hash) {
+            if self.var_of[i] != usize::MAX {
+                c[self.var_of[i]] += 1.;
+            }
+        }
+        for i in iter_bits(negative_hash) {
+            if self.var_of[i] != usize::MAX {
+                c[self.var_of[i]] -= 1.;
+            }
+        }
+        c
+    }
+
+    /// w(hash)の全体に対する割合の最大値の上界（不等式に出てこないアイテムは全体に含めない）
+    pub fn max_weight(&self, hash: u128) -> Option<f64> {
+        let c = self.objective(hash, 0);
+        let (_, dual) = maximize(&self.rows, &self.rhs, &c)?;
+        Some(self.certified_upper_bound(&c, &dual).min(1.))
+    }
+
+    ///
+    /// w(hash)の全体に対する割合の（最小値の下界, 最大値の上界）
+    /// 最小値は、残りのアイテムの割合の最大値から求める
+    /// hashに不等式に出てこないアイテムがあれば、全体との比が決まらないのでNone
+    ///
+    pub fn weight_bounds(&self, hash: u128) -> Option<(f64, f64)> {
+        if hash & !self.items_hash != 0 {
+            return None;
+        }
+        let max_complement = self.max_weight(self.items_hash & !hash)?;
+        Some(((1. - max_complement).max(0.), self.max_weight(hash)?))
+    }
+
+    pub fn item_bounds(&self, item: usize) -> Option<(f64, f64)> {
+        self.group_bounds(&[item])
+    }
+
+    pub fn group_bounds(&self, group: &[usize]) -> Option<(f64, f64)> {
+        self.weight_bounds(group.iter().fold(0, |acc, i| acc | 1 << i))
+    }
+
+    ///
+    /// w(left) <= w(right)が、全ての不等式から確かに言えるか
+    /// leftに不等式に出てこないアイテムがあれば、いくらでも重くできるので言えない
+    ///
+    pub fn is_certainly_lighter(&self, left_hash: u128, right_hash: u128) -> bool {
+        if left_hash & !self.items_hash != 0 {
+            return false;
+        }
+        let c = self.objective(left_hash, right_hash);
+        match maximize(&self.rows, &self.rhs, &c) {
+            Some((max_diff, dual)) => {
+                max_diff <= EPS && self.certified_upper_bound(&c, &dual) <= EPS
+            }
+            None => false,
+        }
+    }
+
+    ///
+    /// 双対解yから、元の係数だけを使ってmax c·xの上界を求める（単体表の丸め誤差によらない）
+    ///
+    /// y >= 0（負の成分は0にする）とし、δ_j = max(c_j - (yA)_j, 0)とすると、実行可能なxについて
+    /// c·x <= yAx + δ·x <= yb + max_j δ_j（最後の行の正規化Σx <= 1を使う）
+    ///
+    fn certified_upper_bound(&self, c: &[f64], dual: &[f64]) -> f64 {
+        let y: Vec<f64> = dual.iter().map(|e| e.max(0.)).collect();
+        if y.iter().any(|e| !e.is_finite()) {
+            return f64::INFINITY;
+        }
+        let max_violation = (0..c.len())
+            .map(|j| {
+                let ya: f64 = (0..self.rows.len()).map(|i| y[i] * self.rows[i][j]).sum();
+                (c[j] - ya).max(0.)
+            })
+            .fold(0., f64::max);
+        let yb: f64 = (0..self.rows.len()).map(|i| y[i] * self.rhs[i]).sum();
+        yb + max_violation
+    }
+}
+
+///
+/// max c·x s.t. Ax <= b, x >= 0 を解き、最大値と双対解（制約ごとの乗数）を返す
+/// 原点が実行可能でない（bに負の成分がある）、非有界、または反復回数の上限に達した場合はNone
+///
+fn maximize(a: &[Vec<f64>], b: &[f64], c: &[f64]) -> Option<(f64, Vec<f64>)> {
+    if b.iter().any(|e| *e < 0.) {
+        return None;
+    }
+    let (m, n) = (a.len(), c.len());
+    let width = n + m + 1;
+    // 単体表: m行の制約と目的関数の行。列は変数、スラック変数、右辺
+    let mut table = vec![0.; (m + 1) * width];
+    for i in 0..m {
+        table[i * width..i * width + n].copy_from_slice(&a[i]);
+        table[i * width + n + i] = 1.;
+        table[i * width + width - 1] = b[i];
+    }
+    for j in 0..n {
+        table[m * width + j] = -c[j];
+    }
+    let mut basis: Vec<usize> = (n..n + m).collect();
+
+    for _ in 0..SIMPLEX_MAX_ITERATION {
+        // Blandの規則: 改善する列のうち番号が最小のもの
+        let Some(col) = (0..n + m).find(|j| table[m * width + j] < -EPS) else {
+            // 目的関数の行のスラック変数の列が、双対解になる
+            let dual = table[m * width + n..m * width + n + m].to_vec();
+            return Some((table[m * width + width - 1], dual));
+        };
+        let mut pivot_row = None;
+        let mut best = (f64::INFINITY, usize::MAX);
+        for i in 0..m {
+            let coef = table[i * width + col];
+            if coef <= PIVOT_EPS {
+                continue;
+            }
+            let ratio = table[i * width + width - 1] / coef;
+            if ratio < best.0 - EPS || (ratio < best.0 + EPS && basis[i] < best.1) {
+                best = (ratio, basis[i]);
+                pivot_row = Some(i);
+            }
+        }
+        let row = pivot_row?;
+        pivot(&mut table, width, m, row, col);
+        basis[row] = col;
+    }
+    None
+}
+
+fn pivot(table: &mut [f64], width: usize, m: usize, row: usize, col: usize) {
+    let p = table[row * width + col];
+    for j in 0..width {
+        table[row * width + j] /= p;
+    }
+    for i in 0..=m {
+        if i == row {
+            continue;
+        }
+        let factor = table[i * width + col];
+        if factor.abs() <= EPS {
+            continue;
+        }
+        for j in 0..width {
+            table[i * width + j] -= factor * table[row * width + j];
+        }
+    }
+}
+
+impl Balancer {
+    ///
+    /// グラフの探索でわからなかった比較を、最近の比較結果から線形計画で判定する
+    ///
+    /// - 時間の上限を経過時間に比例させ、序盤に使い切らないようにする
+    /// - 同じ組を前に解いた時から比較結果が増えていなければ、不等式も目的関数も同じなので解かない
+    ///   （わかった組はキャッシュされるので、わからなかった組だけを覚えておく）
+    /// - どちら向きにも言えない組（両側に不等式に出てこないアイテムがある）は解かない
+    ///
+    pub fn linear_result(&mut self, left_hash: u128, right_hash: u128) -> BalanceResult {
+        let stats = &mut self.linear_stats;
+        if stats.seconds > LINEAR_TIME_BUDGET * time::elapsed_seconds() / TIME_LIMIT
+            || self.comparisons.is_empty()
+        {
+            return BalanceResult::Unknown;
+        }
+        if stats.undecided_comparison_count != self.comparisons.len() {
+            stats.undecided.clear();
+            stats.undecided_comparison_count = self.comparisons.len();
+        }
+        if stats.undecided.contains(&(left_hash, right_hash))
+            || stats.undecided.contains(&(right_hash, left_hash))
+        {
+            stats.skipped_count += 1;
+            return BalanceResult::Unknown;
+        }
+        let window = linear_window(&self.comparisons);
+        let items_hash = window.iter().fold(0, |acc, (l, h)| acc | l | h);
+        if left_hash & !items_hash != 0 && right_hash & !items_hash != 0 {
+            stats.skipped_count += 1;
+            return BalanceResult::Unknown;
+        }
+        let start = time::elapsed_seconds();
+        let bounds = LinearBounds::new(window);
+        let result = if bounds.is_certainly_lighter(left_hash, right_hash) {
+            BalanceResult::Left
+        } else if bounds.is_certainly_lighter(right_hash, left_hash) {
+            BalanceResult::Right
+        } else {
+            BalanceResult::Unknown
+        };
+        stats.solve_count += 1;
+        if result == BalanceResult::Unknown {
+            stats.undecided.insert((left_hash, right_hash));
+        } else {
+            stats.decided_count += 1;
+        }
+        stats.seconds += time::elapsed_seconds() - start;
+        result
+    }
+
+    ///
+    /// 集合の重さの、最近の比較結果に出てくるアイテム全体に対する割合の（下界, 上界）
+    ///
+    /// - アイテムごとの範囲を足し合わせる（集合ごとに解くより緩いが、比較結果が増えるまで使い回せる）
+    /// - 不等式に出てこないアイテムがあるか、時間の上限を超えたらNone
+    ///
+    pub fn linear_weight_bounds(&mut self, items: &[usize]) -> Option<(f64, f64)> {
+        let stats = &mut self.linear_stats;
+        if stats.bounds_comparison_count != self.comparisons.len() {
+            stats.item_bounds.clear();
+            stats.bounds_comparison_count = self.comparisons.len();
+        }
+        let window = linear_window(&self.comparisons);
+        let mut bounds = None;
+        let (mut lower, mut upper) = (0., 0.);
+        for &item in items.iter() {
+            let item_bounds = match stats.item_bounds.get(&item) {
+                Some(item_bounds) => *item_bounds,
+                None => {
+                    if stats.bounds_seconds > LINEAR_BOUNDS_TIME_BUDGET {
+                        return None;
+                    }
+                    let start = time::elapsed_seconds();
+                    let item_bounds = bounds
+                        .get_or_insert_with(|| LinearBounds::new(window))
+                        .item_bounds(item);
+                    stats.bounds_solve_count += 1;
+                    stats.bounds_seconds += time::elapsed_seconds() - start;
+                    stats.item_bounds.insert(item, item_bounds);
+                    item_bounds
+                }
+            };
+            let (item_lower, item_upper) = item_bounds?;
+            lower += item_lower;
+            upper += item_upper;
+        }
+        Some((lower, upper))
+    }
+
+    ///
+    /// 軽いグループのitems_aと重いグループのitems_bを入れ替える変更が、重さの範囲から確かに不採用になるか
+    /// （exchange_itemsの条件 w(A) < w(B) と w(L \ A) <= w(H \ B) のどちらかが、確かに成り立たない）
+    ///
+    pub fn is_exchange_certainly_rejected(
+        &mut self,
+        items_a: &[usize],
+        items_b: &[usize],
+        lighter_group: &[usize],
+        heavier_group: &[usize],
+    ) -> bool {
+        let is_certainly_heavier = |balancer: &mut Balancer, left: &[usize], right: &[usize]| match (
+            balancer.linear_weight_bounds(left),
+            balancer.linear_weight_bounds(right),
+        ) {
+            (Some((left_lower, _)), Some((_, right_upper))) => left_lower > right_upper + EPS,
+            _ => false,
+        };
+        let rest = |group: &[usize], items: &[usize]| -> Vec<usize> {
+            group
+                .iter()
+                .filter(|i| !items.contains(i))
+                .copied()
+                .collect()
+        };
+        let is_rejected = (!items_a.is_empty() && is_certainly_heavier(self, items_a, items_b))
+            || is_certainly_heavier(
+                self,
+                &rest(lighter_group, items_a),
+                &rest(heavier_group, items_b),
+            );
+        if is_rejected {
+            self.linear_stats.screened_count += 1;
+        }
+        is_rejected
+    }
+}
+
+/// 線形計画に使う、最近の比較結果
+fn linear_window(comparisons: &[(u128, u128)]) -> &[(u128, u128)] {
+    &comparisons[comparisons
+        .len()
+        .saturating_sub(LINEAR_MAX_CONSTRAINT_COUNT)..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-7;
+
+    /// xorshift（テストの再現性のため）
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self, max: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % max
+        }
+    }
+
+    fn max_value(a: &[Vec<f64>], b: &[f64], c: &[f64]) -> Option<f64> {
+        maximize(a, b, c).map(|(value, _)| value)
+    }
+
+    ///
+    /// 頂点を全て列挙して最大値を求める（有界な問題のみ）
+    /// Ax <= bとx >= 0のうちn個を等式にした連立方程式の解で、実行可能なものが頂点
+    ///
+    fn brute_force_maximize(a: &[Vec<f64>], b: &[f64], c: &[f64]) -> f64 {
+        let n = c.len();
+        let mut planes: Vec<(Vec<f64>, f64)> = a.iter().cloned().zip(b.iter().copied()).collect();
+        for j in 0..n {
+            let mut row = vec![0.; n];
+            row[j] = 1.;
+            planes.push((row, 0.));
+        }
+        let mut best = f64::NEG_INFINITY;
+        for mask in 0u32..1 << planes.len() {
+            if mask.count_ones() as usize != n {
+                continue;
+            }
+            let chosen: Vec<&(Vec<f64>, f64)> = (0..planes.len())
+                .filter(|i| mask >> i & 1 == 1)
+                .map(|i| &planes[i])
+                .collect();
+            let Some(x) = solve_linear(&chosen) else {
+                continue;
+            };
+            let feasible = x.iter().all(|e| *e >= -TOLERANCE)
+                && a.iter().zip(b.iter()).all(|(row, bi)| {
+                    row.iter().zip(x.iter()).map(|(p, q)| p * q).sum::<f64>() <= bi + TOLERANCE
+                });
+            if feasible {
+                best = best.max(c.iter().zip(x.iter()).map(|(p, q)| p * q).sum());
+            }
+        }
+        best
+    }
+
+    /// ガウスの消去法。正則でなければNone
+    fn solve_linear(planes: &[&(Vec<f64>, f64)]) -> Option<Vec<f64>> {
+        let n = planes.len();
+        let mut m: Vec<Vec<f64>> = planes
+            .iter()
+            .map(|(row, rhs)| row.iter().copied().chain([*rhs]).collect())
+            .collect();
+        for col in 0..n {
+            let pivot = (col..n).max_by(|i, j| m[*i][col].abs().total_cmp(&m[*j][col].abs()))?;
+            if m[pivot][col].abs() < 1e-9 {
+                return None;
+            }
+            m.swap(col, pivot);
+            let pivot_row = m[col].clone();
+            for (i, row) in m.iter_mut().enumerate() {
+                if i != col {
+                    let factor = row[col] / pivot_row[col];
+                    for (e, p) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                        *e -= factor * p;
+                    }
+                }
+            }
+        }
+        Some((0..n).map(|i| m[i][n] / m[i][i]).collect())
+    }
+
+    #[test]
+    fn maximize_solves_small_lp() {
+        // max 3x + 2y s.t. x + y <= 4, x + 3y <= 6, x <= 3 -> x = 3, y = 1
+        let a = vec![vec![1., 1.], vec![1., 3.], vec![1., 0.]];
+        let (value, dual) = maximize(&a, &[4., 6., 3.], &[3., 2.]).unwrap();
+        assert!((value - 11.).abs() < TOLERANCE);
+        // 双対解: y = (2, 0, 1)、yb = 11
+        let expected = [2., 0., 1.];
+        for (y, e) in dual.iter().zip(expected.iter()) {
+            assert!((y - e).abs() < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn maximize_terminates_on_degenerate_lp() {
+        // Bealeの例: 最大係数の規則では巡回するが、Blandの規則では止まる。最大値は1.25
+        let a = vec![
+            vec![0.25, -8., -1., 9.],
+            vec![0.5, -12., -0.5, 3.],
+            vec![0., 0., 1., 0.],
+        ];
+        let value = max_value(&a, &[0., 0., 1.], &[0.75, -20., 0.5, -6.]).unwrap();
+        assert!((value - 1.25).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn maximize_rejects_unbounded_and_infeasible_lp() {
+        // x - y <= 1ではxとyを同時にいくらでも大きくできる
+        assert_eq!(max_value(&[vec![1., -1.]], &[1.], &[1., 0.]), None);
+        // x <= -1はx >= 0と両立しない（原点が実行可能でない問題は扱わない）
+        assert_eq!(max_value(&[vec![1.]], &[-1.], &[1.]), None);
+    }
+
+    #[test]
+    fn maximize_matches_brute_force() {
+        let mut rng = TestRng(1);
+        for _ in 0..300 {
+            let n = 1 + rng.next(3) as usize;
+            let m = 1 + rng.next(4) as usize;
+            let mut a: Vec<Vec<f64>> = (0..m)
+                .map(|_| (0..n).map(|_| rng.next(5) as f64 - 2.).collect())
+                .collect();
+            let mut b: Vec<f64> = (0..m).map(|_| rng.next(3) as f64).collect();
+            // 有界にするための正規化（比較結果の問題と同じ形）
+            a.push(vec![1.; n]);
+            b.push(1.);
+            let c: Vec<f64> = (0..n).map(|_| rng.next(5) as f64 - 2.).collect();
+            let value = max_value(&a, &b, &c).unwrap();
+            let expected = brute_force_maximize(&a, &b, &c);
+            assert!(
+                (value - expected).abs() < TOLERANCE,
+                "{:?} {:?} {:?}: {} != {}",
+                a,
+                b,
+                c,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn linear_result_skips_only_the_same_undecided_pair() {
+        // 時間の上限は経過時間に比例するので、解く時間より十分長く経たせておく
+        time::start_clock();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut balancer = Balancer::new();
+        balancer.comparisons.push((1 << 0, 1 << 1));
+        balancer.comparisons.push((1 << 1, 1 << 2));
+        assert_eq!(balancer.linear_result(1 << 0, 1 << 2), BalanceResult::Left);
+        // 3は不等式に出てこないので、どちら向きにも言えない
+        assert_eq!(
+            balancer.linear_result(1 << 0, 1 << 3),
+            BalanceResult::Unknown
+        );
+        assert_eq!(balancer.linear_stats.solve_count, 2);
+        // 同じ組は、比較結果が増えるまで解かない
+        assert_eq!(
+            balancer.linear_result(1 << 3, 1 << 0),
+            BalanceResult::Unknown
+        );
+        assert_eq!(balancer.linear_stats.skipped_count, 1);
+        // 比較結果が増えていなくても、目的関数が違う組は解く
+        assert_eq!(
+            balancer.linear_result(1 << 1, 1 << 3),
+            BalanceResult::Unknown
+        );
+        assert_eq!(balancer.linear_stats.solve_count, 3);
+        // 比較結果が増えたら、同じ組も解き直す
+        balancer.comparisons.push((1 << 2, 1 << 3));
+        assert_eq!(balancer.linear_result(1 << 0, 1 << 3), BalanceResult::Left);
+        assert_eq!(balancer.linear_stats.solve_count, 4);
+        assert_eq!(balancer.linear_stats.skipped_count, 1);
+    }
+
+    ///
+    /// 真の重さで正しい比較結果だけから、誤った関係を確かと言わないこと
+    /// 大きな問題では、丸め誤差で残った小さな係数で掃き出すと、最大値を小さく見積もっていた
+    ///
+    /// 重さweightsで、ランダムな集合の組をcount回比べた結果
+    fn random_comparisons(rng: &mut TestRng, weights: &[i64], count: usize) -> Vec<(u128, u128)> {
+        let n = weights.len();
+        let weight = |hash: u128| iter_bits(hash).map(|i| weights[i]).sum::<i64>();
+        let mut comparisons = vec![];
+        while comparisons.len() < count {
+            let (mut left, mut right) = (0u128, 0u128);
+            for _ in 0..1 + rng.next(8) {
+                let i = rng.next(n as u64);
+                if rng.next(2) == 0 {
+                    left |= 1 << i;
+                } else {
+                    right |= 1 << i;
+                }
+            }
+            right &= !left;
+            if left == 0 || right == 0 {
+                continue;
+            }
+            if weight(left) <= weight(right) {
+                comparisons.push((left, right));
+            } else {
+                comparisons.push((right, left));
+            }
+        }
+        comparisons
+    }
+
+    #[test]
+    fn certainly_lighter_is_sound_for_true_weights() {
+        let mut rng = TestRng(12345);
+        // 修正前は2つ目の問題で、誤った関係を確かと言っていた
+        for _ in 0..3 {
+            let n = 40;
+            let weights: Vec<i64> = (0..n).map(|_| 1 + rng.next(100000) as i64).collect();
+            let comparisons = random_comparisons(&mut rng, &weights, 2 * n);
+            let bounds = LinearBounds::new(&comparisons);
+            for _ in 0..50 {
+                let (a, b) = (rng.next(n as u64) as usize, rng.next(n as u64) as usize);
+                if a == b {
+                    continue;
+                }
+                if bounds.is_certainly_lighter(1 << a, 1 << b) {
+                    assert!(weights[a] <= weights[b], "{} > {}", weights[a], weights[b]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn weight_bounds_of_chain() {
+        // w0 <= w1 <= w2、w0 + w1 <= w2
+        let bounds = LinearBounds::new(&[(1 << 0, 1 << 1), (1 << 1, 1 << 2), (0b011, 0b100)]);
+        let (lower, upper) = bounds.item_bounds(0).unwrap();
+        assert!(lower.abs() < TOLERANCE && (upper - 0.25).abs() < TOLERANCE);
+        let (lower, upper) = bounds.item_bounds(2).unwrap();
+        assert!((lower - 0.5).abs() < TOLERANCE && (upper - 1.).abs() < TOLERANCE);
+        let (lower, upper) = bounds.group_bounds(&[0, 1]).unwrap();
+        assert!(lower.abs() < TOLERANCE && (upper - 0.5).abs() < TOLERANCE);
+        // 不等式に出てこないアイテムは、全体との比が決まらない
+        assert_eq!(bounds.item_bounds(3), None);
+        assert_eq!(bounds.group_bounds(&[0, 3]), None);
+    }
+
+    #[test]
+    fn weight_bounds_contain_true_weights() {
+        let mut rng = TestRng(777);
+        for _ in 0..3 {
+            let n = 30;
+            let weights: Vec<i64> = (0..n).map(|_| 1 + rng.next(100000) as i64).collect();
+            let total = weights.iter().sum::<i64>() as f64;
+            let comparisons = random_comparisons(&mut rng, &weights, 2 * n);
+            let bounds = LinearBounds::new(&comparisons);
+            for (item, weight) in weights.iter().enumerate() {
+                let (lower, upper) = bounds.item_bounds(item).unwrap();
+                let ratio = *weight as f64 / total;
+                assert!(lower <= ratio + TOLERANCE && ratio <= upper + TOLERANCE);
+            }
+            for _ in 0..10 {
+                let group: Vec<usize> = (0..n).filter(|_| rng.next(3) == 0).collect();
+                let (lower, upper) = bounds.group_bounds(&group).unwrap();
+                let ratio = group.iter().map(|i| weights[*i]).sum::<i64>() as f64 / total;
+                assert!(lower <= ratio + TOLERANCE && ratio <= upper + TOLERANCE);
+            }
+        }
+    }
+}
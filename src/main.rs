@@ -1,28 +1,63 @@
 mod action;
 mod def;
+mod endgame;
+mod estimator;
+#[cfg(feature = "local")]
+mod estimator_report;
+mod eviction;
+mod graph_io;
+mod group_state;
+mod inference;
 mod interactor;
+mod json;
+mod linear_bounds;
+mod merge_insertion;
+mod order_estimator;
+mod rank;
+mod score_eval;
+mod set_index;
+mod stagnation;
+mod tabu;
+mod tournament;
 mod util;
+#[cfg(feature = "local")]
+mod verifier;
 
 use crate::action::*;
 use crate::def::*;
+use crate::endgame::*;
+use crate::estimator::*;
+#[cfg(feature = "local")]
+use crate::estimator_report::*;
+use crate::group_state::*;
 use crate::interactor::*;
+use crate::order_estimator::*;
+use crate::rank::*;
+use crate::stagnation::*;
+use crate::tournament::*;
 use crate::util::*;
+#[cfg(feature = "local")]
+use crate::verifier::*;
 
-fn select_g_idx_pair(input: &Input) -> (usize, usize) {
+///
+/// 重さの差を縮める軽いグループと重いグループの順位を選ぶ
+/// 両隣との大小関係が確認済みでない順位は、本当の順位と違う可能性があるので選ばない
+///
+fn select_g_idx_pair(input: &Input, rank: &dyn Ranking) -> (usize, usize) {
     const P: f64 = 0.3;
     let par = 1
-        + (time::elapsed_seconds() * (input.d as f64 / 3.).min(5.) / (TIME_LIMIT - 0.1)).round()
+        + (time::elapsed_seconds() * (input.d as f64 / 3.).min(5.) / ENDGAME_START_TIME).round()
             as usize;
     let mut lighter_g_idx = 0;
     let mut heavier_g_idx = input.d - 1;
     for i in 0..par.min(input.d / 2) {
-        if rnd::nextf() < P {
+        if rnd::nextf() < P && rank.is_position_certain(i) {
             lighter_g_idx = i;
             break;
         }
     }
     for i in ((input.d - input.d.min(par)).max(input.d / 2)..input.d).rev() {
-        if rnd::nextf() < P {
+        if rnd::nextf() < P && rank.is_position_certain(i) {
             heavier_g_idx = i;
             break;
         }
@@ -30,8 +65,82 @@ fn select_g_idx_pair(input: &Input) -> (usize, usize) {
     (lighter_g_idx, heavier_g_idx)
 }
 
+///
+/// クエリが少ない時は、比較回数が少ないmerge-insertion sortを使う
+/// ローカルでは環境変数SORT_METHOD（quick, merge）で指定できる
+///
+fn select_sort_method(input: &Input) -> SortMethod {
+    const PAR: usize = 4; // :param
+    #[cfg(feature = "local")]
+    match std::env::var("SORT_METHOD").as_deref() {
+        Ok("quick") => return SortMethod::QuickSort,
+        Ok("merge") => return SortMethod::MergeInsertion,
+        _ => {}
+    }
+    if input.q <= input.n * PAR {
+        SortMethod::MergeInsertion
+    } else {
+        SortMethod::QuickSort
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum RankMethod {
+    Sort,
+    Tournament,
+}
+
+///
+/// ソートの比較回数（D log D程度）がクエリ数に対して多すぎる時は、
+/// 全体をソートせずにトーナメントで軽い方・重い方だけの順位を持つ
+/// ローカルでは環境変数RANK_METHOD（sort, tournament）で指定できる
+///
+/// NOTE: Q <= 6Nのケースで比べた限りではソートの方が良かったので、閾値は大きめにしている
+///
+fn select_rank_method(input: &Input) -> RankMethod {
+    const PAR: f64 = 0.6; // :param
+    #[cfg(feature = "local")]
+    match std::env::var("RANK_METHOD").as_deref() {
+        Ok("sort") => return RankMethod::Sort,
+        Ok("tournament") => return RankMethod::Tournament,
+        _ => {}
+    }
+    let sort_cost = input.d as f64 * (input.d as f64).log2();
+    if sort_cost > input.q as f64 * PAR {
+        RankMethod::Tournament
+    } else {
+        RankMethod::Sort
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum SearchMethod {
+    HillClimbing,
+    Annealing,
+}
+
+///
+/// 差が縮まる変更だけを採用する山登りか、推定した重さで悪化する変更も採用する焼きなましか
+/// 山登りを使い、焼きなましは環境変数SEARCH_METHOD=annealで指定した時だけ序盤に使う
+///
+/// NOTE: 推定した重さの誤差で悪化する変更が多く、どの範囲でも山登りの方が良かった
+///
+fn select_search_method() -> SearchMethod {
+    match std::env::var("SEARCH_METHOD").as_deref() {
+        Ok("anneal") => SearchMethod::Annealing,
+        _ => SearchMethod::HillClimbing,
+    }
+}
+
+/// 焼きなましの開始時の温度（推定した重さは平均1の単位）
+const ANNEAL_START_TEMPERATURE: f64 = 0.002; // :param
+/// 焼きなましをやめて山登りに切り替える進み具合（時間とクエリの割合の大きい方）
+const ANNEAL_END_PROGRESS: f64 = 0.15; // :param
+
 fn solve(input: &Input, interactor: &mut Interactor) {
     const PAR: f64 = 1.0;
+    const REPAIR_COUNT: usize = 2; // :param
+    const EXCHANGE_SWEEP_COUNT: usize = 5; // :param
     let mut balancer = Balancer::new();
 
     // ランダムにグループに割り振る
@@ -39,53 +148,270 @@ fn solve(input: &Input, interactor: &mut Interactor) {
     for i in 0..input.n {
         groups[i % input.d].push(i);
     }
+    let mut groups = GroupState::new(input.n, groups);
 
     // ソートして順位をつける
-    let mut rank = sort_groups(&groups, input, interactor, &mut balancer);
-    eprintln!("after_sort: {} / {}", interactor.query_count, input.q);
+    let rank_method = select_rank_method(input);
+    let rank: Result<Box<dyn Ranking>, QueryError> = match rank_method {
+        RankMethod::Sort => {
+            let sort_method = select_sort_method(input);
+            eprintln!("sort_method: {:?}", sort_method);
+            sort_groups(&groups, sort_method, interactor, &mut balancer)
+                .map(|order| Box::new(Rank::new(order, &groups, &mut balancer)) as Box<dyn Ranking>)
+        }
+        RankMethod::Tournament => Tournament::new(&groups, interactor, &mut balancer)
+            .map(|tournament| Box::new(tournament) as Box<dyn Ranking>),
+    };
+    let mut rank = match rank {
+        Ok(rank) => rank,
+        Err(e) => {
+            // 順位がなければ探索できないので、最初の割り振りのまま出力する
+            eprintln!("failed to rank groups: {}", e);
+            let d = groups_to_output_d(&groups, input);
+            interactor.output_d(&d, false);
+            return;
+        }
+    };
+    eprintln!(
+        "after_sort: {} / {} ({:?})",
+        interactor.query_count, input.q, rank_method
+    );
+
+    // ローカルでは、真の重さがわかれば各actionの後に順位と比較結果を確認する
+    #[cfg(feature = "local")]
+    let mut verifier = Verifier::from_env();
+    #[cfg(feature = "local")]
+    if let Some(verifier) = verifier.as_mut() {
+        verifier.verify(
+            &groups,
+            rank.as_ref(),
+            &balancer,
+            "sort",
+            interactor.query_count,
+        );
+    }
 
     let mut trial_count = 0;
     let mut move_adopted_count = 0;
     let mut swap_adopted_count = 0;
     let mut swap2_adopted_count = 0;
+    let mut exchange_adopted_count = 0;
+    let mut chain_adopted_count = 0;
+    let mut resplit_adopted_count = 0;
+
+    // move, swap, swap2, exchange, chain, resplitの累積確率
+    let action_p = [
+        [0.5, 0.9, 0.95, 1.0, 1.0, 1.0],
+        [0.1, 0.2, 0.7, 0.9, 0.95, 1.0],
+    ];
+    let mut estimator = McmcEstimator::new(input);
+    // MCMCの推定値が安定するまでは、アイテム単体の順位から安く推定した値を使う
+    let mut order_estimator = OrderStatisticEstimator::new(input);
+    let mut stagnation = StagnationMonitor::new(input);
+    let search_method = select_search_method();
+    eprintln!("search_method: {:?}", search_method);
+    let mut anneal_adopted_count = 0;
+    // ローカルでは、真の重さがわかれば推定器の精度を一定のクエリ数ごとに記録する
+    #[cfg(feature = "local")]
+    let mut estimator_report = EstimatorReport::from_env();
 
-    let action_p = [[0.5, 0.9, 1.0], [0.1, 0.2, 1.0]];
+    // 問い合わせに失敗したら（クエリを使い切った時も）、探索をやめる
+    let mut query_error = None;
 
-    while interactor.query_count < input.q && time::elapsed_seconds() < TIME_LIMIT - 0.1 {
+    // 終盤の処理のためのクエリと時間を残して終える
+    let endgame_query_budget = endgame_query_budget(input);
+    'search: while interactor.query_count + stagnation.reserved_query_count() + endgame_query_budget
+        < input.q
+        && time::elapsed_seconds() < ENDGAME_START_TIME
+    {
         trial_count += 1;
 
+        #[cfg(feature = "local")]
+        if let Some(report) = estimator_report.as_mut() {
+            if report.is_due(interactor.query_count) {
+                order_estimator.update(&balancer);
+                record_estimator_accuracy(
+                    report,
+                    interactor.query_count,
+                    input,
+                    &order_estimator,
+                    &estimator,
+                );
+            }
+        }
+
         let stage = if time::elapsed_seconds() < PAR { 0 } else { 1 };
 
-        let (lighter_g_idx, heavier_g_idx) = select_g_idx_pair(input);
+        let (lighter_g_idx, heavier_g_idx) = select_g_idx_pair(input, rank.as_ref());
+
+        // 使う順位の周りの、確認済みでない大小関係だけを直す
+        for g_idx in [lighter_g_idx, heavier_g_idx] {
+            if let Err(e) =
+                rank.repair_position(&groups, g_idx, REPAIR_COUNT, interactor, &mut balancer)
+            {
+                query_error = Some(e);
+                break 'search;
+            }
+        }
+
+        let progress = (time::elapsed_seconds() / ENDGAME_START_TIME)
+            .max(interactor.query_count as f64 / input.q as f64);
+        let is_annealing =
+            search_method == SearchMethod::Annealing && progress < ANNEAL_END_PROGRESS;
 
         let p = rnd::nextf();
-        let action = if p < action_p[stage][0] {
-            action_move
+        let action_idx = if is_annealing {
+            6
+        } else if p < action_p[stage][0] {
+            0
         } else if p < action_p[stage][1] {
-            action_swap
+            1
+        } else if p < action_p[stage][2] {
+            2
+        } else if p < action_p[stage][3] {
+            3
+        } else if p < action_p[stage][4] {
+            4
         } else {
-            action_swap2
+            5
         };
 
         trial_count += 1;
-        if action(
-            heavier_g_idx,
-            lighter_g_idx,
-            &mut groups,
-            &mut rank,
-            input,
-            &mut balancer,
-            interactor,
-        ) {
-            if action == action_move {
-                move_adopted_count += 1;
-                eprintln!("[{} / {}] adopt move", interactor.query_count, input.q);
-            } else if action == action_swap {
-                swap_adopted_count += 1;
-                eprintln!("[{} / {}] adopt swap", interactor.query_count, input.q);
-            } else if action == action_swap2 {
-                swap2_adopted_count += 1;
-                eprintln!("[{} / {}] adopt swap2", interactor.query_count, input.q);
+        let result = if action_idx == 6 {
+            // 温度は線形に下げ、ANNEAL_END_PROGRESSで0になる
+            let temperature = ANNEAL_START_TEMPERATURE * (1. - progress / ANNEAL_END_PROGRESS);
+            action_anneal(
+                temperature,
+                &mut groups,
+                rank.as_mut(),
+                &mut balancer,
+                interactor,
+                &mut estimator,
+            )
+        } else if action_idx == 3 {
+            let weight_estimator: &dyn WeightEstimator = if stage == 0 {
+                order_estimator.update(&balancer);
+                &order_estimator
+            } else {
+                estimator.update(&balancer, EXCHANGE_SWEEP_COUNT);
+                &estimator
+            };
+            action_exchange(
+                heavier_g_idx,
+                lighter_g_idx,
+                &mut groups,
+                rank.as_mut(),
+                input,
+                &mut balancer,
+                interactor,
+                weight_estimator,
+            )
+        } else if action_idx == 4 {
+            action_chain(
+                heavier_g_idx,
+                lighter_g_idx,
+                rnd::nextf() < 0.5,
+                &mut groups,
+                rank.as_mut(),
+                input,
+                &mut balancer,
+                interactor,
+            )
+        } else {
+            let action = match action_idx {
+                0 => action_move,
+                1 => action_swap,
+                2 => action_swap2,
+                _ => action_resplit,
+            };
+            action(
+                heavier_g_idx,
+                lighter_g_idx,
+                &mut groups,
+                rank.as_mut(),
+                input,
+                &mut balancer,
+                interactor,
+            )
+        };
+        let adopted = match result {
+            Ok(adopted) => adopted,
+            Err(e) => {
+                query_error = Some(e);
+                break;
+            }
+        };
+        if adopted {
+            match action_idx {
+                0 => {
+                    move_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt move", interactor.query_count, input.q);
+                }
+                1 => {
+                    swap_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt swap", interactor.query_count, input.q);
+                }
+                2 => {
+                    swap2_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt swap2", interactor.query_count, input.q);
+                }
+                3 => {
+                    exchange_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt exchange", interactor.query_count, input.q);
+                }
+                4 => {
+                    chain_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt chain", interactor.query_count, input.q);
+                }
+                6 => anneal_adopted_count += 1,
+                _ => {
+                    resplit_adopted_count += 1;
+                    eprintln!("[{} / {}] adopt resplit", interactor.query_count, input.q);
+                }
+            }
+        }
+
+        #[cfg(feature = "local")]
+        if let Some(verifier) = verifier.as_mut() {
+            let action_name = [
+                "move", "swap", "swap2", "exchange", "chain", "resplit", "anneal",
+            ][action_idx];
+            verifier.verify(
+                &groups,
+                rank.as_ref(),
+                &balancer,
+                action_name,
+                interactor.query_count,
+            );
+        }
+
+        // 採用がしばらくなければ、摂動して局所解から抜け出す
+        if adopted {
+            stagnation.record_adoption(interactor.query_count, trial_count);
+            // 構成が変わったグループについての記録は使われないので消す
+            balancer.expire_tabu(&groups);
+        } else if stagnation.is_stagnant(input, interactor.query_count, trial_count) {
+            if let Err(e) = stagnation.perturb(
+                trial_count,
+                &mut groups,
+                rank.as_mut(),
+                interactor,
+                &mut balancer,
+            ) {
+                query_error = Some(e);
+                break;
+            }
+            eprintln!("[{} / {}] perturb", interactor.query_count, input.q);
+            balancer.expire_tabu(&groups);
+            #[cfg(feature = "local")]
+            if let Some(verifier) = verifier.as_mut() {
+                verifier.verify(
+                    &groups,
+                    rank.as_ref(),
+                    &balancer,
+                    "perturb",
+                    interactor.query_count,
+                );
             }
         }
 
@@ -93,29 +419,174 @@ fn solve(input: &Input, interactor: &mut Interactor) {
         interactor.output_d(&d, true);
     }
 
+    // 残ったクエリで、一番重いグループと一番軽いグループの間の変更を全て確認する
+    let mut endgame_adopted_count = 0;
+    if query_error.is_none() {
+        query_error = run_endgame(
+            input,
+            stagnation.reserved_query_count(),
+            &mut groups,
+            rank.as_mut(),
+            &mut estimator,
+            &mut balancer,
+            interactor,
+            &mut endgame_adopted_count,
+        )
+        .err();
+    }
+    if let Some(e) = query_error.as_ref() {
+        eprintln!(
+            "[{} / {}] stop querying: {}",
+            interactor.query_count, input.q, e
+        );
+    }
+    if endgame_adopted_count > 0 {
+        stagnation.record_adoption(interactor.query_count, trial_count);
+    }
+
+    #[cfg(feature = "local")]
+    if let Some(report) = estimator_report.as_mut() {
+        order_estimator.update(&balancer);
+        record_estimator_accuracy(
+            report,
+            interactor.query_count,
+            input,
+            &order_estimator,
+            &estimator,
+        );
+    }
+
+    // 摂動後の状態が最良の状態より良いと確認できなければ戻す
+    // 比べられなかった場合は、摂動の後に採用があれば今の状態のままにする
+    match stagnation.finish(input, &mut groups, rank.as_mut(), interactor, &mut balancer) {
+        Ok(true) => {
+            eprintln!("[{} / {}] revert to best", interactor.query_count, input.q);
+            balancer.expire_tabu(&groups);
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("failed to compare with the best state: {}", e),
+    }
+
     if interactor.query_count < input.q {
         eprintln!("remaining query:     {}", input.q - interactor.query_count);
     }
 
-    // 必要ないクエリを消化する
+    // ジャッジはちょうどQ回の問い合わせを求める
+    // 終盤の処理で確認できる変更がなくなった後（時間切れを含む）に残ったクエリだけを消化する
     while interactor.query_count < input.q {
-        interactor.output_query(&vec![0], &vec![1]);
+        if let Err(e) = interactor.output_query(&[0], &[1]) {
+            eprintln!("failed to consume remaining query: {}", e);
+            break;
+        }
     }
 
     eprintln!("trial_count:         {trial_count}");
     eprintln!("move_adopted_count:  {move_adopted_count}");
     eprintln!("swap_adopted_count:  {swap_adopted_count}");
     eprintln!("swap2_adopted_count: {swap2_adopted_count}");
+    eprintln!("exchange_adopted_count: {exchange_adopted_count}");
+    eprintln!("chain_adopted_count: {chain_adopted_count}");
+    eprintln!("resplit_adopted_count: {resplit_adopted_count}");
+    eprintln!("endgame_adopted_count: {endgame_adopted_count}");
+    eprintln!("anneal_adopted_count: {anneal_adopted_count}");
+    eprintln!("stale_rank_count:    {}", rank.stale_count());
+    balancer.provenance_stats.print();
+    balancer.set_index_stats.print();
+    balancer.tabu.print();
+    balancer.linear_stats.print();
+    balancer.memory_stats().print();
+    estimator.print();
+    if let Some(distribution) = estimator.score_distribution(&groups) {
+        distribution.print("estimated_score");
+    }
+    order_estimator.print();
+    stagnation.print();
+    #[cfg(feature = "local")]
+    if let Some(verifier) = verifier.as_ref() {
+        verifier.print();
+    }
+    #[cfg(feature = "local")]
+    if let Some(report) = estimator_report.as_ref() {
+        report.print();
+    }
+
+    #[cfg(feature = "local")]
+    if let Ok(path) = std::env::var("GRAPH_OUTPUT") {
+        if let Err(e) = balancer.write_graph(&path) {
+            eprintln!("failed to write graph to {}: {}", path, e);
+        }
+    }
 
     let d = groups_to_output_d(&groups, input);
     interactor.output_d(&d, false);
 }
 
+/// MCMCはサンプリングを始めるまで推定値が初期値のままなので、始めてから記録する
+#[cfg(feature = "local")]
+fn record_estimator_accuracy(
+    report: &mut EstimatorReport,
+    query_count: usize,
+    input: &Input,
+    order_estimator: &OrderStatisticEstimator,
+    estimator: &McmcEstimator,
+) {
+    let mut estimators: Vec<(&'static str, &dyn WeightEstimator)> =
+        vec![("order", order_estimator)];
+    if estimator.has_samples() {
+        estimators.push(("mcmc", estimator));
+    }
+    report.record(query_count, input.q, &estimators);
+}
+
 fn main() {
     time::start_clock();
 
     let mut interactor = Interactor::new();
-    let input = interactor.read_input();
+    let input = match interactor.read_input() {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("failed to read input: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     solve(&input, &mut interactor);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// 途中でジャッジとのやり取りに失敗しても、solveは問い合わせをやめて出力まで進む
+    #[test]
+    fn query_error_stops_solve() {
+        time::start_clock();
+        let input = Input {
+            n: 30,
+            d: 5,
+            q: 200,
+        };
+        const FAIL_AT: usize = 20;
+        let sent = Rc::new(Cell::new(0));
+        let sent_by_judge = sent.clone();
+        let mut interactor = Interactor::with_judge(&input, move |left_v, right_v| {
+            sent_by_judge.set(sent_by_judge.get() + 1);
+            if sent_by_judge.get() == FAIL_AT {
+                return Err(QueryError::Protocol("unexpected end of input".to_owned()));
+            }
+            Ok(
+                if left_v.iter().sum::<usize>() <= right_v.iter().sum::<usize>() {
+                    BalanceResult::Left
+                } else {
+                    BalanceResult::Right
+                },
+            )
+        });
+        solve(&input, &mut interactor);
+        // 失敗した後は、ジャッジに何も送らない
+        assert_eq!(sent.get(), FAIL_AT);
+        assert_eq!(interactor.query_count, FAIL_AT);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::util::*;
+
+///
+/// Ford–Johnson法（merge-insertion sort）で昇順に並べる
+/// 比較回数が情報理論的な下限に近いので、クエリが少ないケースで使う
+///
+/// is_lighter(a, b): w(a) <= w(b)ならtrue
+///
+/// 1. 2個ずつ組にして比較し、重い方だけを再帰的にソートする
+/// 2. 一番軽い組の軽い方を先頭に入れる
+/// 3. 残りの軽い方を、Jacobsthal数の順に、組の重い方より前の範囲に二分探索で挿入する
+///
+pub fn merge_insertion_sort(
+    items: Vec<usize>,
+    is_lighter: &mut impl FnMut(usize, usize) -> bool,
+) -> Vec<usize> {
+    if items.len() <= 1 {
+        return items;
+    }
+
+    let mut partner = FastHashMap::default(); // 重い方 -> 軽い方
+    let mut heavier_items = vec![];
+    for pair in items.chunks_exact(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if is_lighter(a, b) {
+            partner.insert(b, a);
+            heavier_items.push(b);
+        } else {
+            partner.insert(a, b);
+            heavier_items.push(a);
+        }
+    }
+    let straggler = if items.len() % 2 == 1 {
+        Some(items[items.len() - 1])
+    } else {
+        None
+    };
+
+    let sorted_heavier = merge_insertion_sort(heavier_items, is_lighter);
+
+    // pend[k] = (挿入するアイテム, 上限となる重い方のアイテム)
+    let mut chain = vec![partner[&sorted_heavier[0]]];
+    chain.extend(sorted_heavier.iter().copied());
+    let mut pend: Vec<(usize, Option<usize>)> = sorted_heavier[1..]
+        .iter()
+        .map(|a| (partner[a], Some(*a)))
+        .collect();
+    if let Some(e) = straggler {
+        pend.push((e, None));
+    }
+
+    for k in insertion_order(pend.len()) {
+        let (item, upper) = pend[k];
+        let mut hi = match upper {
+            Some(upper) => chain.iter().position(|e| *e == upper).unwrap(),
+            None => chain.len(),
+        };
+        let mut lo = 0;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if is_lighter(item, chain[mid]) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        chain.insert(lo, item);
+    }
+
+    chain
+}
+
+///
+/// 挿入する順番を返す
+/// Jacobsthal数 t_k = 1, 3, 5, 11, 21, ... を区切りとして、各区間を後ろから挿入すると、
+/// 各挿入が2^k - 1個以下の範囲への二分探索になる
+///
+fn insertion_order(pend_len: usize) -> Vec<usize> {
+    // pend[i]は全体で見るとi + 2番目の軽い方（1番目はchainの先頭に入れてある）
+    let mut order = vec![];
+    let (mut prev, mut cur) = (1usize, 3usize); // t_{k-1}, t_k
+    let mut done = 1;
+    while done < pend_len + 1 {
+        let end = cur.min(pend_len + 1);
+        for j in (done + 1..=end).rev() {
+            order.push(j - 2);
+        }
+        done = end;
+        (prev, cur) = (cur, cur + 2 * prev);
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の乱数（xorshift）
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self, m: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % m
+        }
+
+        fn permutation(&mut self, n: usize) -> Vec<usize> {
+            let mut items: Vec<usize> = (0..n).collect();
+            for i in (1..n).rev() {
+                items.swap(i, self.next(i as u64 + 1) as usize);
+            }
+            items
+        }
+    }
+
+    /// weights[item]の昇順に並べ、比較回数と一緒に返す
+    fn sort_by_weight(weights: &[usize]) -> (Vec<usize>, usize) {
+        let mut count = 0;
+        let sorted = merge_insertion_sort((0..weights.len()).collect(), &mut |a, b| {
+            count += 1;
+            weights[a] <= weights[b]
+        });
+        (sorted, count)
+    }
+
+    fn is_sorted(weights: &[usize], sorted: &[usize]) -> bool {
+        let mut items = sorted.to_vec();
+        items.sort();
+        items == (0..weights.len()).collect::<Vec<_>>()
+            && sorted.windows(2).all(|w| weights[w[0]] <= weights[w[1]])
+    }
+
+    /// Ford–Johnson法の最悪の比較回数 Σ_{k=1}^{n} ceil(log2(3k/4))
+    fn ford_johnson_bound(n: usize) -> usize {
+        (1..=n)
+            .map(|k| {
+                let mut bits = 0;
+                while (1 << bits) * 4 < 3 * k {
+                    bits += 1;
+                }
+                bits
+            })
+            .sum()
+    }
+
+    #[test]
+    fn ford_johnson_bound_values() {
+        let expected = [0, 0, 1, 3, 5, 7, 10, 13, 16, 19, 22, 26, 30];
+        for (n, count) in expected.iter().enumerate() {
+            assert_eq!(ford_johnson_bound(n), *count);
+        }
+        assert_eq!(ford_johnson_bound(22), 71);
+    }
+
+    #[test]
+    fn sorts_random_permutations() {
+        let mut rng = TestRng(42);
+        for n in 0..=40 {
+            for _ in 0..50 {
+                let weights = rng.permutation(n);
+                let (sorted, count) = sort_by_weight(&weights);
+                assert!(
+                    is_sorted(&weights, &sorted),
+                    "{:?} -> {:?}",
+                    weights,
+                    sorted
+                );
+                assert!(count <= ford_johnson_bound(n), "n = {}: {}", n, count);
+            }
+        }
+    }
+
+    #[test]
+    fn worst_comparison_count_matches_bound() {
+        // 上限を超えないことに加えて、上限に達する並びがあることを確かめる
+        let mut rng = TestRng(7);
+        for n in [5, 12, 22] {
+            let max_count = (0..5000)
+                .map(|_| sort_by_weight(&rng.permutation(n)).1)
+                .max()
+                .unwrap();
+            assert_eq!(max_count, ford_johnson_bound(n), "n = {}", n);
+        }
+    }
+}
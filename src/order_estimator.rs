@@ -0,0 +1,136 @@
+use crate::def::*;
+use crate::estimator::*;
+
+///
+/// アイテム単体同士の大小関係だけから、重さを安く推定する
+///
+/// 1. ItemOrderで、aより軽いことがわかっているアイテム数lと、重いことがわかっているアイテム数hを数える
+/// 2. aの順位（0始まり、軽い順）は[l, n - 1 - h]のどこかなので、その中央を近似の順位とする
+/// 3. 平均1の指数分布からn個取った時の、その順位の順序統計量の期待値を推定値とする
+///    k番目に小さい値の期待値は 1/n + 1/(n-1) + ... + 1/(n-k+1)（順位が整数でなければ線形補間する）
+///
+/// グループの重さは、WeightEstimatorの既定のgroup_weightで、各アイテムの順序統計量の期待値の和になる
+/// 期待値は線形なので、これはグループの重さの和の期待値（全アイテムならn）の近似になる
+///
+/// MCMCと違ってグループ同士の比較結果は使わないが、1回の更新がO(N)で済む
+/// l, hが変わったアイテムだけ推定値を計算し直す
+///
+pub struct OrderStatisticEstimator {
+    max_weight: f64,
+    expected: Vec<f64>, // expected[k]: k番目（1始まり）に小さい値の期待値（expected[0] = 0）
+    known_counts: Vec<(u32, u32)>, // (軽いことがわかっている数, 重いことがわかっている数)
+    weights: Vec<f64>,
+    update_count: usize,
+    recomputed_item_count: usize,
+}
+
+impl OrderStatisticEstimator {
+    pub fn new(input: &Input) -> OrderStatisticEstimator {
+        let n = input.n;
+        let mut expected = vec![0.; n + 1];
+        for k in 1..=n {
+            expected[k] = expected[k - 1] + 1. / (n - k + 1) as f64;
+        }
+        let mut estimator = OrderStatisticEstimator {
+            max_weight: n as f64 / input.d as f64,
+            expected,
+            known_counts: vec![(u32::MAX, u32::MAX); n],
+            weights: vec![1.; n],
+            update_count: 0,
+            recomputed_item_count: 0,
+        };
+        for i in 0..n {
+            estimator.recompute(i, 0, 0);
+        }
+        estimator
+    }
+
+    /// balancerに記録された、アイテム単体同士の大小関係の変化を反映する
+    pub fn update(&mut self, balancer: &Balancer) {
+        self.update_count += 1;
+        for i in 0..self.weights.len() {
+            let lighter_count = (balancer.item_order.lighter(i) & !(1 << i)).count_ones();
+            let heavier_count = (balancer.item_order.heavier(i) & !(1 << i)).count_ones();
+            if self.known_counts[i] != (lighter_count, heavier_count) {
+                self.recompute(i, lighter_count, heavier_count);
+            }
+        }
+    }
+
+    fn recompute(&mut self, i: usize, lighter_count: u32, heavier_count: u32) {
+        let n = self.weights.len();
+        self.known_counts[i] = (lighter_count, heavier_count);
+        // 等しいアイテムがあると範囲が逆転することがあるので、小さい方に揃える
+        let lowest = lighter_count as f64;
+        let highest = ((n - 1) as f64 - heavier_count as f64).max(lowest);
+        let rank = (lowest + highest) / 2. + 1.; // 1始まり
+        let (k, frac) = (rank.floor() as usize, rank.fract());
+        let value = if k >= n {
+            self.expected[n]
+        } else {
+            self.expected[k] * (1. - frac) + self.expected[k + 1] * frac
+        };
+        self.weights[i] = value.min(self.max_weight);
+        self.recomputed_item_count += 1;
+    }
+
+    pub fn print(&self) {
+        eprintln!("order_update_count:     {}", self.update_count);
+        eprintln!("order_recomputed_items: {}", self.recomputed_item_count);
+    }
+}
+
+impl WeightEstimator for OrderStatisticEstimator {
+    fn weight(&self, i: usize) -> f64 {
+        self.weights[i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPS: f64 = 1e-9;
+
+    /// 平均1の指数分布からn個取った時の、k番目（1始まり）に小さい値の期待値
+    fn expected_order_statistic(n: usize, k: usize) -> f64 {
+        (0..k).map(|j| 1. / (n - j) as f64).sum()
+    }
+
+    #[test]
+    fn group_weight_is_sum_of_expected_order_statistics() {
+        let input = Input { n: 8, d: 2, q: 100 };
+        let mut balancer = Balancer::new();
+        // 0 < 1 < ... < 7の順がわかっていれば、iの順位はi
+        for i in 0..input.n - 1 {
+            balancer.item_order.add(i, i + 1);
+        }
+        let mut estimator = OrderStatisticEstimator::new(&input);
+        estimator.update(&balancer);
+        for i in 0..input.n {
+            let expected = expected_order_statistic(input.n, i + 1);
+            assert!((estimator.weight(i) - expected).abs() < EPS);
+        }
+        let group = [1, 4, 6];
+        let expected: f64 = group
+            .iter()
+            .map(|i| expected_order_statistic(input.n, i + 1))
+            .sum();
+        assert!((estimator.group_weight(&group) - expected).abs() < EPS);
+        // 順序統計量の期待値の和は、n個の和の期待値n
+        let all: Vec<usize> = (0..input.n).collect();
+        assert!((estimator.group_weight(&all) - input.n as f64).abs() < EPS);
+    }
+
+    #[test]
+    fn unknown_items_get_the_middle_order_statistic() {
+        let input = Input { n: 8, d: 2, q: 100 };
+        let estimator = OrderStatisticEstimator::new(&input);
+        // 順位は[0, 7]の中央の3.5（0始まり）なので、4番目と5番目の期待値の間
+        let expected = (expected_order_statistic(8, 4) + expected_order_statistic(8, 5)) / 2.;
+        for i in 0..input.n {
+            assert!((estimator.weight(i) - expected).abs() < EPS);
+        }
+        assert!((estimator.group_weight(&[0, 1, 2]) - 3. * expected).abs() < EPS);
+    }
+}
@@ -0,0 +1,435 @@
+use std::ops::Index;
+
+use crate::def::*;
+use crate::interactor::*;
+
+///
+/// グループの順位の持ち方
+/// actionは軽い方からi番目のグループを選び、変更後にupdateで順位を更新する
+/// 比較を使うメソッドは、問い合わせに失敗したらそのエラーを返す
+///
+pub trait Ranking: Index<usize, Output = usize> {
+    /// グループの重さが変わった時に順位を更新する（update_rankと同じ）
+    #[allow(clippy::too_many_arguments)]
+    fn update(
+        &mut self,
+        groups: &[Vec<usize>],
+        from_up: bool,
+        lighter_g_idx: usize,
+        heavier_g_idx: usize,
+        input: &Input,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError>;
+
+    /// グループの重さがどちらに変わったかわからない
+    fn invalidate(&mut self, g_idx: usize);
+
+    /// グループが重くなった
+    fn invalidate_above(&mut self, g_idx: usize);
+
+    /// 確認済みでない大小関係を最大max_count個確認する
+    fn repair(
+        &mut self,
+        groups: &[Vec<usize>],
+        max_count: usize,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError>;
+
+    ///
+    /// i番目の順位が確かでなければ、確かにするための大小関係を最大max_count個確認する
+    /// 使う順位の近くから直すので、使わない順位のためにクエリを消費しにくい
+    ///
+    fn repair_position(
+        &mut self,
+        groups: &[Vec<usize>],
+        i: usize,
+        max_count: usize,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        if self.is_position_certain(i) {
+            return Ok(true);
+        }
+        self.repair(groups, max_count, interactor, balancer)
+    }
+
+    /// i番目のグループの順位が確かか
+    fn is_position_certain(&self, i: usize) -> bool;
+
+    /// グループの順位
+    fn position(&self, g_idx: usize) -> usize;
+
+    fn stale_count(&self) -> usize;
+}
+
+///
+/// グループを軽い順に並べた順位
+///
+/// 隣り合うグループの大小関係ごとに、今のグループの構成で確認済みかどうかを持つ
+/// グループの構成が変わると、その周りの大小関係は確認済みではなくなる（stale）
+///
+pub struct Rank {
+    order: Vec<usize>,
+    certain: Vec<bool>, // certain[i]: w(order[i]) <= w(order[i + 1])が確認済み
+}
+
+impl Index<usize> for Rank {
+    type Output = usize;
+
+    fn index(&self, i: usize) -> &usize {
+        &self.order[i]
+    }
+}
+
+impl Rank {
+    ///
+    /// ソート済みの順位から作る
+    /// 隣り合うグループの大小関係は、問い合わせをせずにわかるものだけ確認済みにする
+    ///
+    pub fn new(order: Vec<usize>, groups: &[Vec<usize>], balancer: &mut Balancer) -> Rank {
+        let certain = (0..order.len().saturating_sub(1))
+            .map(|i| {
+                matches!(
+                    balancer.peek_result(&groups[order[i]], &groups[order[i + 1]]),
+                    BalanceResult::Left | BalanceResult::Equal
+                )
+            })
+            .collect();
+        Rank { order, certain }
+    }
+
+    pub fn set_certain(&mut self, i: usize) {
+        self.certain[i] = true;
+    }
+
+    ///
+    /// order[i] > order[i + 1]がわかった時に入れ替える
+    /// 入れ替えた2つの大小関係は確認済みになり、外側との大小関係は確認済みではなくなる
+    ///
+    pub fn swap(&mut self, i: usize) {
+        self.order.swap(i, i + 1);
+        self.certain[i] = true;
+        if i > 0 {
+            self.certain[i - 1] = false;
+        }
+        if i + 1 < self.certain.len() {
+            self.certain[i + 1] = false;
+        }
+    }
+
+    pub fn remove(&mut self, pos: usize) -> usize {
+        let g_idx = self.order.remove(pos);
+        if self.certain.is_empty() {
+            return g_idx;
+        }
+        if pos < self.certain.len() {
+            self.certain.remove(pos);
+        } else {
+            self.certain.pop();
+        }
+        // 間にあったグループがなくなったので、新たに隣り合う2つの大小関係は確認していない
+        if pos > 0 && pos - 1 < self.certain.len() {
+            self.certain[pos - 1] = false;
+        }
+        g_idx
+    }
+
+    /// 挿入した位置の両隣との大小関係は確認済みではない
+    pub fn insert(&mut self, pos: usize, g_idx: usize) {
+        self.order.insert(pos, g_idx);
+        if self.order.len() == 1 {
+            return;
+        }
+        self.certain.insert(pos.min(self.certain.len()), false);
+        if pos > 0 {
+            self.certain[pos - 1] = false;
+        }
+        if pos < self.certain.len() {
+            self.certain[pos] = false;
+        }
+    }
+
+    ///
+    /// w(order[i]) <= w(order[i + 1])を確認し、逆転していたら入れ替える
+    /// 確認できなかった場合はfalseを返す
+    ///
+    fn check(
+        &mut self,
+        i: usize,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        match balancer.get_result(
+            &groups[self.order[i]],
+            &groups[self.order[i + 1]],
+            interactor,
+        )? {
+            BalanceResult::Left | BalanceResult::Equal => self.certain[i] = true,
+            BalanceResult::Right => self.swap(i),
+            BalanceResult::Unknown => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// グループが軽くなったので、一つ軽いグループとの大小関係は確認済みではなくなる
+    pub fn invalidate_below(&mut self, g_idx: usize) {
+        let pos = self.position(g_idx);
+        if pos > 0 {
+            self.certain[pos - 1] = false;
+        }
+    }
+}
+
+impl Ranking for Rank {
+    fn update(
+        &mut self,
+        groups: &[Vec<usize>],
+        from_up: bool,
+        lighter_g_idx: usize,
+        heavier_g_idx: usize,
+        input: &Input,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        update_rank(
+            self,
+            groups,
+            from_up,
+            lighter_g_idx,
+            heavier_g_idx,
+            input,
+            interactor,
+            balancer,
+        )
+    }
+
+    fn invalidate(&mut self, g_idx: usize) {
+        self.invalidate_below(g_idx);
+        self.invalidate_above(g_idx);
+    }
+
+    /// グループが重くなったので、一つ重いグループとの大小関係は確認済みではなくなる
+    fn invalidate_above(&mut self, g_idx: usize) {
+        let pos = self.position(g_idx);
+        if pos < self.certain.len() {
+            self.certain[pos] = false;
+        }
+    }
+
+    /// i番目のグループの、両隣との大小関係が確認済みか
+    fn is_position_certain(&self, i: usize) -> bool {
+        (i == 0 || self.certain[i - 1]) && (i + 1 >= self.order.len() || self.certain[i])
+    }
+
+    fn position(&self, g_idx: usize) -> usize {
+        self.order.iter().position(|e| *e == g_idx).unwrap()
+    }
+
+    fn stale_count(&self) -> usize {
+        self.certain.iter().filter(|c| !**c).count()
+    }
+
+    ///
+    /// 確認済みでない大小関係を、端に近いものから最大max_count個確認する
+    /// 逆転していたら入れ替える（入れ替えた外側はまた確認が必要になる）
+    ///
+    /// 推論やキャッシュでわかるものはクエリを消費しない
+    /// 確認できなかった場合はfalseを返す
+    ///
+    fn repair(
+        &mut self,
+        groups: &[Vec<usize>],
+        max_count: usize,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        for _ in 0..max_count {
+            let n = self.certain.len();
+            let Some(i) = (0..n)
+                .filter(|i| !self.certain[*i])
+                .min_by_key(|i| (*i).min(n - 1 - *i))
+            else {
+                return Ok(true);
+            };
+            if !self.check(i, groups, interactor, balancer)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    ///
+    /// 確認済みでない大小関係を、i番目に近いものから最大max_count個確認する
+    /// 離れた位置の大小関係でも、確認済みでなければi番目より軽い（重い）グループが隠れている可能性がある
+    ///
+    fn repair_position(
+        &mut self,
+        groups: &[Vec<usize>],
+        i: usize,
+        max_count: usize,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        for _ in 0..max_count {
+            // 大小関係jはj番目とj + 1番目の間にある
+            let Some(j) = (0..self.certain.len())
+                .filter(|j| !self.certain[*j])
+                .min_by_key(|j| if *j < i { i - 1 - *j } else { *j - i })
+            else {
+                return Ok(true);
+            };
+            if !self.check(j, groups, interactor, balancer)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // グループ0..5の重さ（1グループ1アイテム）
+    const WEIGHTS: [i64; 5] = [50, 10, 40, 20, 30];
+
+    fn setup() -> (Vec<Vec<usize>>, Balancer, Interactor) {
+        let input = Input {
+            n: WEIGHTS.len(),
+            d: WEIGHTS.len(),
+            q: 100,
+        };
+        let groups = (0..WEIGHTS.len()).map(|i| vec![i]).collect();
+        (
+            groups,
+            Balancer::new(),
+            Interactor::with_weights(&input, WEIGHTS.to_vec()),
+        )
+    }
+
+    fn certain_positions(rank: &Rank) -> Vec<bool> {
+        (0..WEIGHTS.len())
+            .map(|i| rank.is_position_certain(i))
+            .collect()
+    }
+
+    /// 確認済みの大小関係が、真の重さと矛盾しない
+    fn assert_consistent(rank: &Rank) {
+        for i in 0..rank.certain.len() {
+            if rank.certain[i] {
+                assert!(WEIGHTS[rank[i]] <= WEIGHTS[rank[i + 1]], "{:?}", rank.order);
+            }
+        }
+    }
+
+    #[test]
+    fn repair_sorts_and_makes_every_position_certain() {
+        let (groups, mut balancer, mut interactor) = setup();
+        let mut rank = Rank::new(vec![0, 1, 2, 3, 4], &groups, &mut balancer);
+        assert_eq!(rank.stale_count(), 4);
+        assert_eq!(certain_positions(&rank), vec![false; 5]);
+
+        // 1個ずつ確認すると、確認した大小関係だけが確認済みになる
+        assert!(rank
+            .repair(&groups, 1, &mut interactor, &mut balancer)
+            .unwrap());
+        assert_eq!(interactor.query_count, 1);
+        assert_consistent(&rank);
+
+        assert!(rank
+            .repair(&groups, 100, &mut interactor, &mut balancer)
+            .unwrap());
+        assert_eq!(rank.order, vec![1, 3, 4, 2, 0]);
+        assert_eq!(rank.stale_count(), 0);
+        assert_eq!(certain_positions(&rank), vec![true; 5]);
+
+        // 比較結果は覚えているので、作り直しても問い合わせずに確認済みになる
+        let query_count = interactor.query_count;
+        let rank = Rank::new(vec![1, 3, 4, 2, 0], &groups, &mut balancer);
+        assert_eq!(rank.stale_count(), 0);
+        assert_eq!(interactor.query_count, query_count);
+    }
+
+    #[test]
+    fn repair_position_checks_nearest_relation_first() {
+        let (groups, mut balancer, mut interactor) = setup();
+        let mut rank = Rank::new(vec![1, 3, 4, 2, 0], &groups, &mut balancer);
+        // 0番目の両隣は大小関係1つだけ
+        assert!(rank
+            .repair_position(&groups, 0, 1, &mut interactor, &mut balancer)
+            .unwrap());
+        assert!(rank.is_position_certain(0));
+        assert_eq!(rank.stale_count(), 3);
+        assert_eq!(interactor.query_count, 1);
+        // 両隣が確認済みでも、離れた位置にi番目より軽いグループが隠れている可能性があるので、次に近いものを確認する
+        assert!(rank
+            .repair_position(&groups, 0, 1, &mut interactor, &mut balancer)
+            .unwrap());
+        assert_eq!(rank.certain, vec![true, true, false, false]);
+        assert_eq!(interactor.query_count, 2);
+    }
+
+    #[test]
+    fn swap_remove_insert_and_invalidate_update_certainty() {
+        let (groups, mut balancer, mut interactor) = setup();
+        let mut rank = Rank::new(vec![1, 3, 2, 4, 0], &groups, &mut balancer);
+        rank.set_certain(0);
+        rank.set_certain(3);
+
+        // w(2) > w(4)がわかった時に入れ替えると、入れ替えた2つの間は確認済み、外側は確認済みではない
+        rank.swap(2);
+        assert_eq!(rank.order, vec![1, 3, 4, 2, 0]);
+        assert_eq!(rank.certain, vec![true, false, true, false]);
+        assert_eq!(
+            certain_positions(&rank),
+            vec![true, false, false, false, false]
+        );
+        assert_eq!(rank.stale_count(), 2);
+        assert!(rank
+            .repair(&groups, 100, &mut interactor, &mut balancer)
+            .unwrap());
+        assert_eq!(rank.order, vec![1, 3, 4, 2, 0]);
+        assert_eq!(rank.stale_count(), 0);
+
+        // 取り除くと、新たに隣り合う2つの大小関係は確認済みではない
+        assert_eq!(rank.remove(2), 4);
+        assert_eq!(rank.order, vec![1, 3, 2, 0]);
+        assert_eq!(rank.certain, vec![true, false, true]);
+        assert_eq!(rank.stale_count(), 1);
+        // 端を取り除いても、残りの大小関係はそのまま
+        assert_eq!(rank.remove(3), 0);
+        assert_eq!(rank.certain, vec![true, false]);
+
+        // 挿入した位置の両隣は確認済みではない
+        rank.insert(1, 4);
+        assert_eq!(rank.order, vec![1, 4, 3, 2]);
+        assert_eq!(rank.certain, vec![false, false, false]);
+        rank.insert(4, 0);
+        assert_eq!(rank.order, vec![1, 4, 3, 2, 0]);
+        assert_eq!(rank.stale_count(), 4);
+        assert!(rank
+            .repair(&groups, 100, &mut interactor, &mut balancer)
+            .unwrap());
+        assert_eq!(rank.order, vec![1, 3, 4, 2, 0]);
+        assert_eq!(rank.stale_count(), 0);
+
+        // 重さの変わったグループの両隣（片側）だけが確認済みではなくなる
+        rank.invalidate(4);
+        assert_eq!(rank.certain, vec![true, false, false, true]);
+        assert_eq!(
+            certain_positions(&rank),
+            vec![true, false, false, false, true]
+        );
+        rank.repair(&groups, 100, &mut interactor, &mut balancer)
+            .unwrap();
+        rank.invalidate_above(1);
+        assert_eq!(rank.certain, vec![false, true, true, true]);
+        rank.invalidate_below(0);
+        assert_eq!(rank.certain, vec![false, true, true, false]);
+        assert_eq!(rank.stale_count(), 2);
+        assert_consistent(&rank);
+    }
+}
@@ -0,0 +1,82 @@
+/// 入力の重さの平均（指数分布のパラメータλ = 1e-5）。推定した重さは平均1の単位なので、これを掛ける
+const WEIGHT_SCALE: f64 = 1e5;
+/// 求める分位点
+pub const SCORE_QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+
+/// サンプルごとのスコアの分布
+#[derive(Debug, Clone)]
+pub struct ScoreDistribution {
+    pub mean: f64,
+    pub quantiles: [f64; SCORE_QUANTILES.len()], // SCORE_QUANTILESの各分位点
+    pub sample_count: usize,
+}
+
+impl ScoreDistribution {
+    pub fn print(&self, name: &str) {
+        eprintln!(
+            "{}: mean {:.0}, quantiles {:?} ({} samples)",
+            name,
+            self.mean,
+            self.quantiles.map(|e| e.round()),
+            self.sample_count
+        );
+    }
+}
+
+/// 重さがweightsの時の、グループ分けのスコア（1 + round(100 * 標準偏差)、丸めない）
+pub fn score_of(weights: &[f64], groups: &[Vec<usize>]) -> f64 {
+    let group_weights: Vec<f64> = groups
+        .iter()
+        .map(|g| g.iter().map(|i| weights[*i]).sum::<f64>() * WEIGHT_SCALE)
+        .collect();
+    let d = group_weights.len() as f64;
+    let mean = group_weights.iter().sum::<f64>() / d;
+    let variance = group_weights
+        .iter()
+        .map(|w| (w - mean) * (w - mean))
+        .sum::<f64>()
+        / d;
+    1. + 100. * variance.sqrt()
+}
+
+///
+/// 比較結果と矛盾しない重さのサンプルそれぞれでスコアを計算し、その分布を返す
+/// サンプルがなければNone
+///
+pub fn evaluate_score(samples: &[&[f64]], groups: &[Vec<usize>]) -> Option<ScoreDistribution> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut scores: Vec<f64> = samples.iter().map(|w| score_of(w, groups)).collect();
+    scores.sort_by(|a, b| a.total_cmp(b));
+    let quantiles = SCORE_QUANTILES.map(|q| {
+        let pos = q * (scores.len() - 1) as f64;
+        let (lo, frac) = (pos.floor() as usize, pos.fract());
+        let hi = (lo + 1).min(scores.len() - 1);
+        scores[lo] * (1. - frac) + scores[hi] * frac
+    });
+    Some(ScoreDistribution {
+        mean: scores.iter().sum::<f64>() / scores.len() as f64,
+        quantiles,
+        sample_count: scores.len(),
+    })
+}
+
+///
+/// proposalの方がcurrentよりスコアが小さい（良い）サンプルの割合
+/// 同じサンプルで比べるので、推定の誤差が両方に同じように効き、差だけを見られる
+///
+pub fn improvement_probability(
+    samples: &[&[f64]],
+    current: &[Vec<usize>],
+    proposal: &[Vec<usize>],
+) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let improved_count = samples
+        .iter()
+        .filter(|w| score_of(w, proposal) < score_of(w, current))
+        .count();
+    Some(improved_count as f64 / samples.len() as f64)
+}
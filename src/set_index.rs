@@ -0,0 +1,247 @@
+use crate::inference::*;
+use crate::util::*;
+
+///
+/// グラフの頂点として存在する集合の索引
+/// add_additional_edgesで推論に使える集合を、全頂点を走査せずに列挙するために使う
+///
+/// - by_item[i]: アイテムiを含む集合
+/// - by_min_item[i]: 最小のアイテムがiである集合（部分集合の列挙に使う）
+/// - by_popcount[k]: 要素数がkの集合（要素数が少ない集合との差分の列挙に使う）
+///
+pub struct SetIndex {
+    nodes: FastHashSet<u128>,
+    by_item: Vec<Vec<u128>>,
+    by_min_item: Vec<Vec<u128>>,
+    by_popcount: Vec<Vec<u128>>,
+}
+
+impl SetIndex {
+    pub fn new() -> SetIndex {
+        SetIndex {
+            nodes: FastHashSet::default(),
+            by_item: vec![vec![]; 128],
+            by_min_item: vec![vec![]; 128],
+            by_popcount: vec![vec![]; 129],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn contains(&self, hash: u128) -> bool {
+        self.nodes.contains(&hash)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = u128> + '_ {
+        self.nodes.iter().copied()
+    }
+
+    /// fを満たす集合だけを残す
+    pub fn retain(&mut self, mut f: impl FnMut(u128) -> bool) {
+        self.nodes.retain(|v| f(*v));
+        let nodes = &self.nodes;
+        for list in self
+            .by_item
+            .iter_mut()
+            .chain(self.by_min_item.iter_mut())
+            .chain(self.by_popcount.iter_mut())
+        {
+            list.retain(|v| nodes.contains(v));
+        }
+    }
+
+    pub fn insert(&mut self, hash: u128) {
+        if hash == 0 || !self.nodes.insert(hash) {
+            return;
+        }
+        for i in iter_bits(hash) {
+            self.by_item[i].push(hash);
+        }
+        self.by_min_item[hash.trailing_zeros() as usize].push(hash);
+        self.by_popcount[hash.count_ones() as usize].push(hash);
+    }
+
+    ///
+    /// v_hashと比べて、以下のいずれかを満たす可能性がある集合を重複なく列挙する
+    /// 1. v_hashを包含する
+    /// 2. v_hashに包含される
+    /// 3. 差分がそれぞれmax_diff個以下
+    ///
+    pub fn candidates(&self, v_hash: u128, max_diff: u32) -> Vec<u128> {
+        let mut candidates = vec![];
+        if v_hash == 0 {
+            return candidates;
+        }
+        let v_count = v_hash.count_ones();
+
+        // 1. v_hashを包含する集合は、v_hashの中で一番出現の少ないアイテムを含む
+        let rarest_item = iter_bits(v_hash)
+            .min_by_key(|i| self.by_item[*i].len())
+            .unwrap();
+        candidates.extend(
+            self.by_item[rarest_item]
+                .iter()
+                .filter(|u| *u & v_hash == v_hash),
+        );
+
+        // 2. v_hashに包含される集合は、最小のアイテムがv_hashに含まれる
+        for i in iter_bits(v_hash) {
+            candidates.extend(self.by_min_item[i].iter().filter(|u| *u & !v_hash == 0));
+        }
+
+        // 3. 差分がmax_diff個以下の集合は、要素数の差がmax_diff以下で、
+        //    v_hashのアイテムのうちmax_diff + 1個のどれかを含む
+        //    v_hashの要素数がmax_diff以下なら、共通部分がない場合もあるので要素数で絞る
+        let is_near = |u: &&u128| {
+            (v_hash & !**u).count_ones() <= max_diff && (**u & !v_hash).count_ones() <= max_diff
+        };
+        if v_count <= max_diff {
+            for k in 1..=(v_count + max_diff).min(128) {
+                candidates.extend(self.by_popcount[k as usize].iter().filter(is_near));
+            }
+        } else {
+            let mut items: Vec<usize> = iter_bits(v_hash).collect();
+            items.sort_by_key(|i| self.by_item[*i].len());
+            for i in items.iter().take(max_diff as usize + 1) {
+                candidates.extend(self.by_item[*i].iter().filter(is_near));
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// add_additional_edgesの計算量の計測結果
+#[derive(Debug, Default, Clone)]
+pub struct SetIndexStats {
+    pub call_count: usize,
+    pub candidate_count: usize,
+    pub node_count: usize,
+    pub max_candidate_count: usize,
+    pub elapsed_seconds: f64,
+}
+
+impl SetIndexStats {
+    pub fn print(&self) {
+        let calls = self.call_count.max(1) as f64;
+        eprintln!("additional_edges_calls:           {}", self.call_count);
+        eprintln!(
+            "additional_edges_candidates_mean: {:.2}",
+            self.candidate_count as f64 / calls
+        );
+        eprintln!(
+            "additional_edges_candidates_max:  {}",
+            self.max_candidate_count
+        );
+        eprintln!(
+            "additional_edges_nodes_mean:      {:.2}",
+            self.node_count as f64 / calls
+        );
+        eprintln!(
+            "additional_edges_us_per_call:     {:.3}",
+            self.elapsed_seconds * 1e6 / calls
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の乱数（xorshift）
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self, m: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % m
+        }
+
+        /// n個のアイテムから、1個以上max_size個以下を選んだ集合
+        fn set(&mut self, n: usize, max_size: u64) -> u128 {
+            let size = 1 + self.next(max_size);
+            (0..size).fold(0, |acc, _| acc | 1 << self.next(n as u64))
+        }
+    }
+
+    fn is_candidate(v_hash: u128, u_hash: u128, max_diff: u32) -> bool {
+        u_hash & v_hash == v_hash
+            || v_hash & u_hash == u_hash
+            || ((v_hash & !u_hash).count_ones() <= max_diff
+                && (u_hash & !v_hash).count_ones() <= max_diff)
+    }
+
+    #[test]
+    fn candidates_contain_every_related_set() {
+        let mut rng = TestRng(1);
+        let mut index = SetIndex::new();
+        for _ in 0..2000 {
+            index.insert(rng.set(30, 8));
+        }
+        index.retain(|v| v.count_ones() != 3);
+        for _ in 0..200 {
+            let v_hash = rng.set(30, 8);
+            let candidates = index.candidates(v_hash, 2);
+            let expected: Vec<u128> = index
+                .nodes()
+                .filter(|u| is_candidate(v_hash, *u, 2))
+                .collect();
+            for u in expected {
+                assert!(candidates.binary_search(&u).is_ok(), "missing {:b}", u);
+            }
+            assert!(candidates.iter().all(|u| index.contains(*u)));
+        }
+    }
+
+    ///
+    /// 頂点数を増やした時の、1回の列挙の候補数と時間を全頂点の走査と比べる
+    /// 列挙の時間は候補数にほぼ比例し、頂点数はevictionでMAX_NODE_COUNT（2048）以下に保たれる
+    /// cargo test --release set_index -- --ignored --nocapture
+    ///
+    #[test]
+    #[ignore]
+    fn bench_candidates_by_node_count() {
+        const QUERY_COUNT: usize = 2000;
+        for node_count in [512, 2048, 8192, 32768] {
+            let mut rng = TestRng(node_count as u64);
+            let mut index = SetIndex::new();
+            while index.len() < node_count {
+                index.insert(rng.set(100, 12));
+            }
+            let queries: Vec<u128> = (0..QUERY_COUNT).map(|_| rng.set(100, 12)).collect();
+
+            let start = std::time::Instant::now();
+            let mut candidate_count = 0;
+            for &v_hash in queries.iter() {
+                candidate_count += index.candidates(v_hash, 2).len();
+            }
+            let index_us = start.elapsed().as_secs_f64() * 1e6 / QUERY_COUNT as f64;
+
+            let start = std::time::Instant::now();
+            let mut related_count = 0;
+            for &v_hash in queries.iter() {
+                related_count += index
+                    .nodes()
+                    .filter(|u| is_candidate(v_hash, *u, 2))
+                    .count();
+            }
+            let scan_us = start.elapsed().as_secs_f64() * 1e6 / QUERY_COUNT as f64;
+
+            eprintln!(
+                "nodes {:>6}: candidates {:>7.1} (related {:>7.1}), index {:>7.2} us/query ({:.3} us/candidate), full scan {:>7.2} us/query",
+                node_count,
+                candidate_count as f64 / QUERY_COUNT as f64,
+                related_count as f64 / QUERY_COUNT as f64,
+                index_us,
+                index_us * QUERY_COUNT as f64 / candidate_count.max(1) as f64,
+                scan_us
+            );
+        }
+    }
+}
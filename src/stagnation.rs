@@ -0,0 +1,415 @@
+use crate::def::*;
+use crate::group_state::*;
+use crate::interactor::*;
+use crate::rank::*;
+use crate::util::*;
+
+/// 採用がないまま、この割合のクエリを使ったら停滞とみなす
+const STAGNATION_QUERY_RATIO: f64 = 0.25; // :param
+/// 採用がないまま、この回数試したら停滞とみなす（推論だけで済んでクエリを使わない場合）
+const STAGNATION_TRIAL_COUNT: usize = 100000; // :param
+/// 摂動で、2つのグループからそれぞれ選んで混ぜ直すアイテム数
+const PERTURB_ITEM_COUNT: usize = 2; // :param
+/// 摂動後に改善するためのクエリが、これだけ残っていなければ摂動しない
+const PERTURB_MIN_REMAINING_RATIO: f64 = 0.3; // :param
+/// 最良の状態と比べる前に、一番軽い・重いグループの順位を確かにするために使うクエリ数（それぞれ）
+const EXTREME_REPAIR_COUNT: usize = 2; // :param
+
+/// 最良の状態と、その時の一番軽いグループ・一番重いグループ
+struct BestState {
+    groups: Vec<Vec<usize>>,
+    lightest: Vec<usize>,
+    heaviest: Vec<usize>,
+}
+
+///
+/// 採用が続かなくなったら、順位が中くらいのグループ同士でアイテムをランダムに入れ替えて局所解から抜け出す
+///
+/// - 摂動の前に、必ず戻る先の状態を記録しておく
+///   前回の摂動から採用があり、最良の状態より悪いと確認できなければ、今の状態で最良の状態を更新する
+/// - 一番軽いグループが軽くなったか、一番重いグループが重くなったことを確認できれば悪いとする
+/// - 最後に、今の状態が悪いと確認できるか、前回の摂動から採用がなければ、最良の状態に戻す
+///   確認のためのクエリを残しておく（reserved_query_count）
+/// - 端のグループは変えないので、摂動の直後にスコアの目安（最大と最小の差）は悪化しない
+///
+pub struct StagnationMonitor {
+    query_window: usize,
+    last_adopted_query_count: usize,
+    last_adopted_trial_count: usize,
+    best: Option<BestState>,
+    perturb_count: usize,
+    best_update_count: usize,
+    reverted: bool,
+    adopted_since_perturb: bool,
+}
+
+impl StagnationMonitor {
+    pub fn new(input: &Input) -> StagnationMonitor {
+        StagnationMonitor {
+            query_window: ((input.q as f64 * STAGNATION_QUERY_RATIO) as usize).max(input.d),
+            last_adopted_query_count: 0,
+            last_adopted_trial_count: 0,
+            best: None,
+            perturb_count: 0,
+            best_update_count: 0,
+            reverted: false,
+            adopted_since_perturb: true,
+        }
+    }
+
+    pub fn record_adoption(&mut self, query_count: usize, trial_count: usize) {
+        self.last_adopted_query_count = query_count;
+        self.last_adopted_trial_count = trial_count;
+        self.adopted_since_perturb = true;
+    }
+
+    /// 最後に最良の状態と比べるために残しておくクエリ数
+    pub fn reserved_query_count(&self) -> usize {
+        if self.best.is_some() {
+            2 + 2 * EXTREME_REPAIR_COUNT
+        } else {
+            0
+        }
+    }
+
+    pub fn is_stagnant(&self, input: &Input, query_count: usize, trial_count: usize) -> bool {
+        let remaining = input
+            .q
+            .saturating_sub(query_count + self.reserved_query_count());
+        if input.d < 4 || (remaining as f64) < input.q as f64 * PERTURB_MIN_REMAINING_RATIO {
+            return false;
+        }
+        query_count - self.last_adopted_query_count >= self.query_window
+            || trial_count - self.last_adopted_trial_count >= STAGNATION_TRIAL_COUNT
+    }
+
+    ///
+    /// 最良の状態を記録してから、順位が端でない2つのグループからk個ずつ選んだアイテムを混ぜて分け直す
+    /// 入れ替えた2つのグループの順位は確認済みではなくなる
+    /// 記録する前に問い合わせに失敗したら、摂動せずにエラーを返す
+    ///
+    pub fn perturb(
+        &mut self,
+        trial_count: usize,
+        groups: &mut GroupState,
+        rank: &mut dyn Ranking,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<(), QueryError> {
+        // 端の2つを除いて2つのグループを選ぶので、4つ以上必要（is_stagnantで確認している）
+        debug_assert!(groups.len() >= 4);
+        self.update_best(groups, rank, interactor, balancer)?;
+
+        let d = groups.len();
+        let i = rnd::gen_range(1, d - 1);
+        let mut j = rnd::gen_range(1, d - 2);
+        if j >= i {
+            j += 1;
+        }
+        let (g_a, g_b) = (rank[i], rank[j]);
+        let item_count = PERTURB_ITEM_COUNT
+            .min(groups[g_a].len() - 1)
+            .min(groups[g_b].len() - 1);
+        // 2つのグループから選んだアイテムを並べ替え、前半をg_a、後半をg_bに入れる
+        let mut items = [
+            pick_items(&groups[g_a], item_count),
+            pick_items(&groups[g_b], item_count),
+        ]
+        .concat();
+        for k in (1..items.len()).rev() {
+            items.swap(k, rnd::gen_range(0, k + 1));
+        }
+        let (to_a, to_b) = items.split_at(item_count);
+        // g_aに入るg_bのアイテムと、g_bに入るg_aのアイテムは同じ数なので、組にして入れ替える
+        let from_b = to_a.iter().filter(|e| groups.group_of(**e) == g_b);
+        let from_a = to_b.iter().filter(|e| groups.group_of(**e) == g_a);
+        let pairs: Vec<(usize, usize)> = from_a.zip(from_b).map(|(a, b)| (*a, *b)).collect();
+        // 入れ替えの途中ではアイテムがどのグループにも入っていないので、まとめて確定する
+        groups.begin();
+        for (item_a, item_b) in pairs {
+            groups.swap_items(item_a, item_b);
+        }
+        groups.commit();
+        rank.invalidate(g_a);
+        rank.invalidate(g_b);
+        self.perturb_count += 1;
+        // 摂動してから次の停滞までを数え直す
+        self.last_adopted_query_count = interactor.query_count;
+        self.last_adopted_trial_count = trial_count;
+        self.adopted_since_perturb = false;
+        Ok(())
+    }
+
+    ///
+    /// 戻る先の状態を記録する
+    /// 最良の状態がまだないか、前回の摂動から採用があって今の状態が悪いと確認できなければ更新する
+    ///
+    fn update_best(
+        &mut self,
+        groups: &GroupState,
+        rank: &mut dyn Ranking,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<(), QueryError> {
+        let (lightest, heaviest) = extreme_groups(groups, rank, interactor, balancer)?;
+        let is_better = match self.best.as_ref() {
+            Some(best) => {
+                self.adopted_since_perturb
+                    && !is_worse(&lightest, &heaviest, best, interactor, balancer)?
+            }
+            None => true,
+        };
+        if is_better {
+            self.best = Some(BestState {
+                groups: groups.to_vec(),
+                lightest,
+                heaviest,
+            });
+            self.best_update_count += 1;
+        }
+        Ok(())
+    }
+
+    ///
+    /// 前回の摂動から採用がないか、今の状態が最良の状態より悪いと確認できれば、最良の状態に戻す
+    /// 戻した場合はtrueを返す
+    /// 比べている途中で問い合わせに失敗したら、戻さずにエラーを返す
+    ///
+    pub fn finish(
+        &mut self,
+        input: &Input,
+        groups: &mut GroupState,
+        rank: &mut dyn Ranking,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let Some(best) = self.best.as_ref() else {
+            return Ok(false);
+        };
+        if self.adopted_since_perturb {
+            let (lightest, heaviest) = extreme_groups(groups, rank, interactor, balancer)?;
+            if !is_worse(&lightest, &heaviest, best, interactor, balancer)? {
+                return Ok(false);
+            }
+        }
+        *groups = GroupState::new(input.n, best.groups.clone());
+        self.reverted = true;
+        Ok(true)
+    }
+
+    pub fn print(&self) {
+        eprintln!("perturb_count:       {}", self.perturb_count);
+        eprintln!("best_update_count:   {}", self.best_update_count);
+        eprintln!("reverted_to_best:    {}", self.reverted);
+    }
+}
+
+/// groupからk個のアイテムを重複なく選ぶ
+fn pick_items(group: &[usize], k: usize) -> Vec<usize> {
+    let mut items = group.to_vec();
+    for i in 0..k {
+        let j = rnd::gen_range(i, items.len());
+        items.swap(i, j);
+    }
+    items.truncate(k);
+    items
+}
+
+///
+/// 一番軽いグループと一番重いグループ
+/// 順位が確かでなければ、少しだけクエリを使って確かにしてから選ぶ（確かにならなくてもそのまま使う）
+///
+fn extreme_groups(
+    groups: &GroupState,
+    rank: &mut dyn Ranking,
+    interactor: &mut Interactor,
+    balancer: &mut Balancer,
+) -> Result<(Vec<usize>, Vec<usize>), QueryError> {
+    let d = groups.len();
+    for i in [0, d - 1] {
+        rank.repair_position(groups, i, EXTREME_REPAIR_COUNT, interactor, balancer)?;
+    }
+    Ok((groups[rank[0]].clone(), groups[rank[d - 1]].clone()))
+}
+
+/// 一番軽いグループが軽くなったか、一番重いグループが重くなったことを確認できたか
+fn is_worse(
+    lightest: &[usize],
+    heaviest: &[usize],
+    best: &BestState,
+    interactor: &mut Interactor,
+    balancer: &mut Balancer,
+) -> Result<bool, QueryError> {
+    Ok(
+        is_lighter_or_equal(heaviest, &best.heaviest, interactor, balancer)? == Some(false)
+            || is_lighter_or_equal(&best.lightest, lightest, interactor, balancer)? == Some(false),
+    )
+}
+
+///
+/// w(a) <= w(b)か
+/// 別の状態のグループ同士は同じアイテムを含むことがあるので、共通部分を除いて比べる
+///
+fn is_lighter_or_equal(
+    a: &[usize],
+    b: &[usize],
+    interactor: &mut Interactor,
+    balancer: &mut Balancer,
+) -> Result<Option<bool>, QueryError> {
+    let only_a: Vec<usize> = a.iter().filter(|e| !b.contains(e)).copied().collect();
+    let only_b: Vec<usize> = b.iter().filter(|e| !a.contains(e)).copied().collect();
+    if only_a.is_empty() {
+        return Ok(Some(true));
+    }
+    if only_b.is_empty() {
+        return Ok(Some(false));
+    }
+    Ok(match balancer.get_result(&only_a, &only_b, interactor)? {
+        BalanceResult::Left | BalanceResult::Equal => Some(true),
+        BalanceResult::Right => Some(false),
+        BalanceResult::Unknown => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const D: usize = 6;
+
+    /// 重さが全て異なる6グループ×3アイテム（グループgはアイテム3g..3g + 3）と、確認済みの順位
+    fn setup() -> (Input, GroupState, Rank, Balancer, Interactor) {
+        let input = Input {
+            n: 3 * D,
+            d: D,
+            q: 1000,
+        };
+        let weights: Vec<i64> = (0..input.n).map(|i| 10 + (i as i64 * 7) % 19).collect();
+        let mut interactor = Interactor::with_weights(&input, weights);
+        let mut balancer = Balancer::new();
+        let groups = GroupState::new(
+            input.n,
+            (0..D).map(|g| (3 * g..3 * g + 3).collect()).collect(),
+        );
+        let mut rank = Rank::new((0..D).collect(), &groups, &mut balancer);
+        rank.repair(&groups, 100, &mut interactor, &mut balancer)
+            .unwrap();
+        assert_eq!(rank.stale_count(), 0);
+        (input, groups, rank, balancer, interactor)
+    }
+
+    #[test]
+    fn perturb_mixes_only_middle_groups() {
+        let (input, mut groups, mut rank, mut balancer, mut interactor) = setup();
+        let mut monitor = StagnationMonitor::new(&input);
+        let before = groups.to_vec();
+        let (lightest, heaviest) = (rank[0], rank[D - 1]);
+        monitor
+            .perturb(0, &mut groups, &mut rank, &mut interactor, &mut balancer)
+            .unwrap();
+
+        assert!(monitor.best.is_some());
+        assert!(!monitor.adopted_since_perturb);
+        assert_eq!(monitor.reserved_query_count(), 2 + 2 * EXTREME_REPAIR_COUNT);
+        // 端のグループは変えず、グループの大きさも変わらない
+        assert_eq!(groups[lightest], before[lightest]);
+        assert_eq!(groups[heaviest], before[heaviest]);
+        let changed: Vec<usize> = (0..D).filter(|g| groups[*g] != before[*g]).collect();
+        assert!(changed.len() <= 2);
+        for g_idx in 0..D {
+            assert_eq!(groups[g_idx].len(), before[g_idx].len());
+        }
+        // 入れ替えたグループの順位は確認済みではない
+        for g_idx in changed {
+            assert!(!rank.is_position_certain(rank.position(g_idx)));
+        }
+    }
+
+    #[test]
+    fn finish_restores_best_groups_without_adoption() {
+        let (input, mut groups, mut rank, mut balancer, mut interactor) = setup();
+        let mut monitor = StagnationMonitor::new(&input);
+        // 最良の状態がなければ何もしない
+        assert!(!monitor
+            .finish(
+                &input,
+                &mut groups,
+                &mut rank,
+                &mut interactor,
+                &mut balancer
+            )
+            .unwrap());
+
+        let before = groups.to_vec();
+        monitor
+            .perturb(0, &mut groups, &mut rank, &mut interactor, &mut balancer)
+            .unwrap();
+        let query_count = interactor.query_count;
+        assert!(monitor
+            .finish(
+                &input,
+                &mut groups,
+                &mut rank,
+                &mut interactor,
+                &mut balancer
+            )
+            .unwrap());
+        assert_eq!(groups.to_vec(), before);
+        assert!(monitor.reverted);
+        // 採用がなければ、比べずに戻す
+        assert_eq!(interactor.query_count, query_count);
+    }
+
+    #[test]
+    fn finish_keeps_adopted_state_unless_it_is_worse() {
+        let (input, mut groups, mut rank, mut balancer, mut interactor) = setup();
+        let mut monitor = StagnationMonitor::new(&input);
+        monitor
+            .perturb(0, &mut groups, &mut rank, &mut interactor, &mut balancer)
+            .unwrap();
+        let perturbed = groups.to_vec();
+        monitor.record_adoption(interactor.query_count, 0);
+        // 端のグループが変わっていないので、悪いとは確認できない
+        assert!(!monitor
+            .finish(
+                &input,
+                &mut groups,
+                &mut rank,
+                &mut interactor,
+                &mut balancer
+            )
+            .unwrap());
+        assert_eq!(groups.to_vec(), perturbed);
+        assert!(!monitor.reverted);
+    }
+
+    #[test]
+    fn finish_reverts_worse_adopted_state() {
+        let (input, mut groups, mut rank, mut balancer, mut interactor) = setup();
+        let mut monitor = StagnationMonitor::new(&input);
+        let before = groups.to_vec();
+        monitor
+            .perturb(0, &mut groups, &mut rank, &mut interactor, &mut balancer)
+            .unwrap();
+        monitor.record_adoption(interactor.query_count, 0);
+
+        // 一番軽いグループから一番重いグループにアイテムを移して、差を広げる
+        let (lightest, heaviest) = (rank[0], rank[D - 1]);
+        groups.begin();
+        groups.move_item(groups[lightest][0], heaviest);
+        groups.commit();
+        rank.invalidate(lightest);
+        rank.invalidate(heaviest);
+        assert!(monitor
+            .finish(
+                &input,
+                &mut groups,
+                &mut rank,
+                &mut interactor,
+                &mut balancer
+            )
+            .unwrap());
+        assert_eq!(groups.to_vec(), before);
+        assert!(monitor.reverted);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::def::*;
+use crate::util::*;
+
+///
+/// 不採用になった提案の記録（タブーリスト）
+///
+/// キーは(動かすアイテムの集合, 移動元のグループの構成, 移動先のグループの構成)
+/// 交換の場合は(両方のアイテムの集合, 軽いグループの構成, 重いグループの構成)とする
+///
+/// 比較結果はキャッシュされているのでクエリは消費しないが、同じ提案を何度も試すのを避ける
+/// 採用した提案を戻す提案も、差が縮まらないとわかっているので記録する
+/// どちらかのグループの構成が変われば同じキーにはならないので、expireで消す
+///
+#[derive(Default)]
+pub struct TabuList {
+    entries: FastHashSet<(u128, u128, u128)>,
+    pub added_count: usize,
+    pub suppressed_count: usize,
+    pub expired_count: usize,
+}
+
+impl TabuList {
+    pub fn print(&self) {
+        eprintln!("tabu_size:              {}", self.entries.len());
+        eprintln!("tabu_added_count:       {}", self.added_count);
+        eprintln!("tabu_suppressed_count:  {}", self.suppressed_count);
+        eprintln!("tabu_expired_count:     {}", self.expired_count);
+    }
+}
+
+impl Balancer {
+    /// 不採用になったことがある提案ならtrue
+    pub fn is_tabu(&mut self, items: &[usize], from_group: &[usize], to_group: &[usize]) -> bool {
+        let key = (
+            self.to_hash(items),
+            self.to_hash(from_group),
+            self.to_hash(to_group),
+        );
+        let is_tabu = self.tabu.entries.contains(&key);
+        if is_tabu {
+            self.tabu.suppressed_count += 1;
+        }
+        is_tabu
+    }
+
+    /// 不採用になった提案を記録する（グループはアイテムを取り除く前の構成）
+    pub fn add_tabu(&mut self, items: &[usize], from_group: &[usize], to_group: &[usize]) {
+        let key = (
+            self.to_hash(items),
+            self.to_hash(from_group),
+            self.to_hash(to_group),
+        );
+        if self.tabu.entries.insert(key) {
+            self.tabu.added_count += 1;
+        }
+    }
+
+    ///
+    /// 採用した提案を戻す提案を記録する（グループはadd_tabuと同じ順で、変更後の構成）
+    /// moveも交換も差が縮まる時だけ採用するので、戻しても差は縮まらない
+    /// 戻す提案では、移動元と移動先（軽い方と重い方）が入れ替わる
+    ///
+    pub fn add_reverse_tabu(
+        &mut self,
+        items: &[usize],
+        first_group: &[usize],
+        second_group: &[usize],
+    ) {
+        self.add_tabu(items, second_group, first_group);
+    }
+
+    /// 今のどのグループの構成とも一致しない記録を消す
+    pub fn expire_tabu(&mut self, groups: &[Vec<usize>]) {
+        let group_hashes: FastHashSet<u128> = groups.iter().map(|g| self.to_hash(g)).collect();
+        let before = self.tabu.entries.len();
+        self.tabu
+            .entries
+            .retain(|(_, from, to)| group_hashes.contains(from) && group_hashes.contains(to));
+        self.tabu.expired_count += before - self.tabu.entries.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_blocks_only_the_recorded_proposal() {
+        let mut balancer = Balancer::new();
+        balancer.add_tabu(&[2], &[0, 1, 2], &[3, 4]);
+        // 集合として同じなら、並び順によらない
+        assert!(balancer.is_tabu(&[2], &[2, 1, 0], &[4, 3]));
+        assert!(!balancer.is_tabu(&[1], &[0, 1, 2], &[3, 4]));
+        assert!(!balancer.is_tabu(&[2], &[0, 1, 2], &[3, 4, 5]));
+        assert_eq!(balancer.tabu.added_count, 1);
+        assert_eq!(balancer.tabu.suppressed_count, 1);
+        // 同じ記録は二重に数えない
+        balancer.add_tabu(&[2], &[0, 1, 2], &[3, 4]);
+        assert_eq!(balancer.tabu.added_count, 1);
+    }
+
+    #[test]
+    fn reverse_entry_blocks_the_reverse_move() {
+        let mut balancer = Balancer::new();
+        // アイテム2を{0, 1, 2}から{3, 4}に移すmoveを採用した
+        balancer.add_reverse_tabu(&[2], &[0, 1], &[2, 3, 4]);
+        assert!(balancer.is_tabu(&[2], &[2, 3, 4], &[0, 1]));
+        // 同じ向きにさらに移す提案は止めない
+        assert!(!balancer.is_tabu(&[3], &[2, 3, 4], &[0, 1]));
+        assert!(!balancer.is_tabu(&[2], &[0, 1], &[2, 3, 4]));
+
+        // 軽い{0, 1}の1と重い{2, 3}の3を入れ替える交換を採用した（キーは軽い方、重い方の順）
+        balancer.add_reverse_tabu(&[1, 3], &[0, 3], &[1, 2]);
+        assert!(balancer.is_tabu(&[1, 3], &[1, 2], &[0, 3]));
+        assert!(!balancer.is_tabu(&[1, 3], &[0, 3], &[1, 2]));
+    }
+
+    #[test]
+    fn expire_drops_entries_whose_group_changed() {
+        let mut balancer = Balancer::new();
+        let mut groups = vec![vec![0, 1, 2], vec![3, 4], vec![5, 6]];
+        balancer.add_tabu(&[2], &groups[0], &groups[1]);
+        balancer.add_tabu(&[5], &groups[2], &groups[1]);
+        balancer.add_tabu(&[6], &groups[2], &groups[0]);
+
+        // 構成が変わらなければ残る
+        balancer.expire_tabu(&groups);
+        assert_eq!(balancer.tabu.entries.len(), 3);
+        assert_eq!(balancer.tabu.expired_count, 0);
+
+        // グループ1の構成が変わると、グループ1についての記録だけ消える
+        groups[1].push(7);
+        balancer.expire_tabu(&groups);
+        assert_eq!(balancer.tabu.expired_count, 2);
+        assert!(!balancer.is_tabu(&[2], &[0, 1, 2], &[3, 4]));
+        assert!(!balancer.is_tabu(&[5], &[5, 6], &[3, 4]));
+        assert!(balancer.is_tabu(&[6], &[5, 6], &[0, 1, 2]));
+
+        // 元の構成に戻っても、消えた記録は戻らない
+        groups[1].pop();
+        balancer.expire_tabu(&groups);
+        assert!(!balancer.is_tabu(&[2], &[0, 1, 2], &[3, 4]));
+    }
+}
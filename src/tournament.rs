@@ -0,0 +1,438 @@
+use std::ops::Index;
+
+use crate::def::*;
+use crate::interactor::*;
+use crate::rank::*;
+
+/// 順位を保持する、軽い方・重い方それぞれのグループ数
+const TOURNAMENT_TOP_K: usize = 2; // :param
+
+///
+/// トーナメント木で、一番軽いグループと一番重いグループの周りだけの順位を保持する
+///
+/// 全体をソートするとD log D回程度の比較が必要だが、
+/// メインループで使うのは軽い方・重い方の数グループだけなので、クエリが少ない時に使う
+///
+/// - 重い方が勝つ木と軽い方が勝つ木を持つ（1回戦は同じ組み合わせなので、比較結果は共有される）
+/// - グループが変わったら、そのグループの葉から根までの試合だけをやり直す
+/// - 上位k個は、勝者を除いて経路をやり直すことを繰り返して求める
+///   変わっていないグループ同士の比較はキャッシュされているのでクエリを消費しない
+///
+/// 順位は、軽い方のk個（軽い順）、残り（順不同）、重い方のk個（軽い順）の順に並べる
+///
+pub struct Tournament {
+    size: usize,
+    k: usize,
+    heavier_tree: MatchTree,
+    lighter_tree: MatchTree,
+    order: Vec<usize>,
+}
+
+/// 各試合の勝者を持つ完全二分木（葉はsize..2 * size）
+#[derive(Clone)]
+struct MatchTree {
+    heavier_wins: bool,
+    winners: Vec<Option<usize>>,
+    stale: Vec<bool>, // stale[node]: 比較できず、勝者を仮に決めている
+}
+
+impl MatchTree {
+    fn new(size: usize, d: usize, heavier_wins: bool) -> MatchTree {
+        let mut winners = vec![None; 2 * size];
+        for g_idx in 0..d {
+            winners[size + g_idx] = Some(g_idx);
+        }
+        MatchTree {
+            heavier_wins,
+            winners,
+            stale: vec![false; 2 * size],
+        }
+    }
+
+    fn winner(&self) -> Option<usize> {
+        self.winners[1]
+    }
+
+    ///
+    /// nodeの試合を行う。比較できなかった場合はfalseを返す
+    /// 問い合わせに失敗した場合も、勝者を仮に決めてからエラーを返す
+    ///
+    fn play(
+        &mut self,
+        node: usize,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let (a, b) = (self.winners[2 * node], self.winners[2 * node + 1]);
+        self.stale[node] = false;
+        let (Some(a), Some(b)) = (a, b) else {
+            self.winners[node] = a.or(b);
+            return Ok(true);
+        };
+        let result = balancer.get_result(&groups[a], &groups[b], interactor);
+        let a_is_heavier = match result {
+            Ok(BalanceResult::Left) => false,  // <
+            Ok(BalanceResult::Right) => true,  // >
+            Ok(BalanceResult::Equal) => false, // =
+            Ok(BalanceResult::Unknown) | Err(_) => {
+                self.winners[node] = Some(a);
+                self.stale[node] = true;
+                return result.map(|_| false);
+            }
+        };
+        self.winners[node] = if a_is_heavier == self.heavier_wins {
+            Some(a)
+        } else {
+            Some(b)
+        };
+        Ok(true)
+    }
+
+    /// 葉から根までの試合をやり直す
+    fn replay(
+        &mut self,
+        leaf: usize,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let mut ok = true;
+        let mut node = leaf / 2;
+        while node >= 1 {
+            ok &= self.play(node, groups, interactor, balancer)?;
+            node /= 2;
+        }
+        Ok(ok)
+    }
+
+    ///
+    /// g_idxのグループが、この木で勝ちやすくなった（strongerがtrue）、または負けやすくなった時に試合をやり直す
+    ///
+    /// - 勝ちやすくなった場合、勝っていた試合は勝ったままなので、負けていた試合からやり直し、負けたら終わる
+    /// - 負けやすくなった場合、負けていた試合は負けたままなので、勝っていた試合だけをやり直す
+    ///
+    fn replay_monotone(
+        &mut self,
+        g_idx: usize,
+        stronger: bool,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let size = self.winners.len() / 2;
+        let mut ok = true;
+        let mut node = (size + g_idx) / 2;
+        while node >= 1 {
+            let was_winner = self.winners[node] == Some(g_idx);
+            if stronger && was_winner {
+                node /= 2;
+                continue;
+            }
+            if !stronger && !was_winner {
+                break;
+            }
+            ok &= self.play(node, groups, interactor, balancer)?;
+            if stronger && self.winners[node] != Some(g_idx) {
+                break;
+            }
+            node /= 2;
+        }
+        Ok(ok)
+    }
+
+    /// 勝ち抜く順に最大k個のグループを返す
+    fn top_k(
+        &self,
+        k: usize,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<(Vec<usize>, bool), QueryError> {
+        let size = self.winners.len() / 2;
+        let mut tree = self.clone();
+        let mut ok = true;
+        let mut top = vec![];
+        while top.len() < k {
+            let Some(g_idx) = tree.winner() else {
+                break;
+            };
+            top.push(g_idx);
+            tree.winners[size + g_idx] = None;
+            ok &= tree.replay(size + g_idx, groups, interactor, balancer)?;
+        }
+        Ok((top, ok))
+    }
+}
+
+impl Index<usize> for Tournament {
+    type Output = usize;
+
+    fn index(&self, i: usize) -> &usize {
+        &self.order[i]
+    }
+}
+
+impl Tournament {
+    /// 問い合わせに失敗したら、そのエラーを返す
+    pub fn new(
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<Tournament, QueryError> {
+        let d = groups.len();
+        let size = d.next_power_of_two();
+        let mut tournament = Tournament {
+            size,
+            k: TOURNAMENT_TOP_K.min(d / 2),
+            heavier_tree: MatchTree::new(size, d, true),
+            lighter_tree: MatchTree::new(size, d, false),
+            order: (0..d).collect(),
+        };
+        for node in (1..size).rev() {
+            tournament
+                .lighter_tree
+                .play(node, groups, interactor, balancer)?;
+            tournament
+                .heavier_tree
+                .play(node, groups, interactor, balancer)?;
+        }
+        tournament.rebuild_order(groups, interactor, balancer)?;
+        Ok(tournament)
+    }
+
+    /// 軽い方・重い方のk個を求め直して順位を並べ直す
+    fn rebuild_order(
+        &mut self,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let d = self.order.len();
+        let (lightest, lighter_ok) = self
+            .lighter_tree
+            .top_k(self.k, groups, interactor, balancer)?;
+        let (heaviest, heavier_ok) = self
+            .heavier_tree
+            .top_k(self.k, groups, interactor, balancer)?;
+        // 比較できなかった場合、両方に同じグループが入ることがあるので、軽い方を優先する
+        let mut order = lightest;
+        let mut top = vec![];
+        for g_idx in heaviest {
+            if !order.contains(&g_idx) {
+                top.push(g_idx);
+            }
+        }
+        for g_idx in 0..d {
+            if !order.contains(&g_idx) && !top.contains(&g_idx) {
+                order.push(g_idx);
+            }
+        }
+        order.extend(top.iter().rev());
+        self.order = order;
+        Ok(lighter_ok && heavier_ok)
+    }
+}
+
+impl Ranking for Tournament {
+    fn update(
+        &mut self,
+        groups: &[Vec<usize>],
+        from_up: bool,
+        lighter_g_idx: usize,
+        heavier_g_idx: usize,
+        _input: &Input,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        let g_idx = if from_up {
+            self.order[heavier_g_idx]
+        } else {
+            self.order[lighter_g_idx]
+        };
+        // from_upならグループが軽くなったので、軽い方が勝つ木で勝ちやすくなる
+        let lighter_ok = self
+            .lighter_tree
+            .replay_monotone(g_idx, from_up, groups, interactor, balancer)?;
+        let heavier_ok = self
+            .heavier_tree
+            .replay_monotone(g_idx, !from_up, groups, interactor, balancer)?;
+        let order_ok = self.rebuild_order(groups, interactor, balancer)?;
+        Ok(lighter_ok && heavier_ok && order_ok)
+    }
+
+    /// 次のrepairでそのグループの試合をやり直す
+    fn invalidate(&mut self, g_idx: usize) {
+        let mut node = (self.size + g_idx) / 2;
+        while node >= 1 {
+            self.lighter_tree.stale[node] = true;
+            self.heavier_tree.stale[node] = true;
+            node /= 2;
+        }
+    }
+
+    fn invalidate_above(&mut self, g_idx: usize) {
+        self.invalidate(g_idx);
+    }
+
+    /// 比較できなかった試合を、葉に近いものからやり直す
+    fn repair(
+        &mut self,
+        groups: &[Vec<usize>],
+        max_count: usize,
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> Result<bool, QueryError> {
+        if self.stale_count() == 0 {
+            return Ok(true);
+        }
+        let mut count = 0;
+        'repair: for node in (1..self.size).rev() {
+            for tree in [&mut self.lighter_tree, &mut self.heavier_tree] {
+                if !tree.stale[node] {
+                    continue;
+                }
+                if count >= max_count {
+                    break 'repair;
+                }
+                if !tree.play(node, groups, interactor, balancer)? {
+                    return Ok(false);
+                }
+                count += 1;
+            }
+        }
+        self.rebuild_order(groups, interactor, balancer)
+    }
+
+    /// 軽い方・重い方のk個に入っていて、試合が全て確認済みなら確か
+    fn is_position_certain(&self, i: usize) -> bool {
+        let d = self.order.len();
+        (i < self.k || i >= d - self.k) && self.stale_count() == 0
+    }
+
+    fn position(&self, g_idx: usize) -> usize {
+        self.order.iter().position(|e| *e == g_idx).unwrap()
+    }
+
+    fn stale_count(&self) -> usize {
+        (1..self.size)
+            .filter(|node| self.lighter_tree.stale[*node] || self.heavier_tree.stale[*node])
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の乱数（xorshift）
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self, m: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % m
+        }
+    }
+
+    /// 重さが全て異なるn個のアイテム
+    fn distinct_weights(rng: &mut TestRng, n: usize) -> Vec<i64> {
+        let mut weights: Vec<i64> = (0..n).map(|i| 1 + i as i64 * 7).collect();
+        for i in (1..n).rev() {
+            weights.swap(i, rng.next(i as u64 + 1) as usize);
+        }
+        weights
+    }
+
+    /// 全ての試合を行った木
+    fn build(
+        d: usize,
+        heavier_wins: bool,
+        groups: &[Vec<usize>],
+        interactor: &mut Interactor,
+        balancer: &mut Balancer,
+    ) -> MatchTree {
+        let size = d.next_power_of_two();
+        let mut tree = MatchTree::new(size, d, heavier_wins);
+        for node in (1..size).rev() {
+            assert!(tree.play(node, groups, interactor, balancer).unwrap());
+        }
+        tree
+    }
+
+    #[test]
+    fn replay_monotone_matches_rebuild() {
+        let mut rng = TestRng(2024);
+        for _ in 0..200 {
+            // グループg_idxは1個のアイテムg_idxから始め、残りのアイテムを足したり取り除いたりする
+            let d = 2 + rng.next(12) as usize;
+            let n = 2 * d;
+            let input = Input { n, d, q: 10000 };
+            let weights = distinct_weights(&mut rng, n);
+            let mut interactor = Interactor::with_weights(&input, weights.clone());
+            let mut balancer = Balancer::new();
+            let mut groups: Vec<Vec<usize>> = (0..d).map(|i| vec![i, d + i]).collect();
+            for heavier_wins in [true, false] {
+                let mut tree = build(d, heavier_wins, &groups, &mut interactor, &mut balancer);
+                for _ in 0..5 {
+                    let g_idx = rng.next(d as u64) as usize;
+                    let heavier = groups[g_idx].len() == 1;
+                    if heavier {
+                        groups[g_idx].push(d + g_idx);
+                    } else {
+                        groups[g_idx].pop();
+                    }
+                    assert!(tree
+                        .replay_monotone(
+                            g_idx,
+                            heavier == heavier_wins,
+                            &groups,
+                            &mut interactor,
+                            &mut balancer
+                        )
+                        .unwrap());
+                    let rebuilt = build(d, heavier_wins, &groups, &mut interactor, &mut balancer);
+                    assert_eq!(tree.winners, rebuilt.winners);
+                    assert!(tree.stale.iter().all(|stale| !stale));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn top_k_matches_sort() {
+        let mut rng = TestRng(99);
+        for d in 1..=16 {
+            let input = Input { n: d, d, q: 10000 };
+            let weights = distinct_weights(&mut rng, d);
+            let mut interactor = Interactor::with_weights(&input, weights.clone());
+            let mut balancer = Balancer::new();
+            let groups: Vec<Vec<usize>> = (0..d).map(|i| vec![i]).collect();
+            let mut sorted: Vec<usize> = (0..d).collect();
+            sorted.sort_by_key(|g_idx| weights[*g_idx]);
+            for k in 1..=d {
+                let lighter_tree = build(d, false, &groups, &mut interactor, &mut balancer);
+                let (lightest, ok) = lighter_tree
+                    .top_k(k, &groups, &mut interactor, &mut balancer)
+                    .unwrap();
+                assert!(ok);
+                assert_eq!(lightest, sorted[..k]);
+                let heavier_tree = build(d, true, &groups, &mut interactor, &mut balancer);
+                let (heaviest, ok) = heavier_tree
+                    .top_k(k, &groups, &mut interactor, &mut balancer)
+                    .unwrap();
+                assert!(ok);
+                assert!(heaviest.iter().eq(sorted.iter().rev().take(k)));
+            }
+
+            // 順位は軽い方のk個（軽い順）、残り、重い方のk個（軽い順）
+            let tournament = Tournament::new(&groups, &mut interactor, &mut balancer).unwrap();
+            let k = tournament.k;
+            assert_eq!(tournament.order[..k], sorted[..k]);
+            assert_eq!(tournament.order[d - k..], sorted[d - k..]);
+            assert_eq!(tournament.stale_count(), 0);
+        }
+    }
+}
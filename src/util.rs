@@ -24,6 +24,13 @@ pub mod rnd {
         assert!(low < high);
         (next() % (high - low)) + low
     }
+
+    /// 標準正規分布（Box-Muller法）
+    pub fn gen_normal() -> f64 {
+        let u1 = nextf().max(1e-12);
+        let u2 = nextf();
+        (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+    }
 }
 
 pub mod time {
@@ -0,0 +1,179 @@
+use crate::def::*;
+use crate::inference::*;
+use crate::rank::*;
+use crate::util::*;
+
+///
+/// 真の重さを使って、順位・比較結果のキャッシュ・グラフの辺が正しいかを確認する（ローカル用）
+/// クエリは消費しない
+///
+/// 真の重さは環境変数AHC025_INPUTで指定した入力ファイル（1行目にN D Q、2行目に重さ）から読む
+/// 最初に見つかった矛盾だけを、直前のactionとクエリ数と一緒に報告する
+///
+pub struct Verifier {
+    weights: Vec<i64>,
+    checked_cached_results: FastHashSet<(u128, u128)>,
+    checked_edges: FastHashSet<(u128, u128)>,
+    check_count: usize,
+    first_error: Option<String>,
+}
+
+/// 環境変数AHC025_INPUTで指定した入力ファイルから、真の重さを読む（ローカル用）
+pub fn read_true_weights() -> Option<Vec<i64>> {
+    let path = std::env::var("AHC025_INPUT").ok()?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_true_weights(&text),
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// 入力ファイルの内容（1行目にN D Q、2行目に重さ）から、真の重さを読む
+pub fn parse_true_weights(text: &str) -> Option<Vec<i64>> {
+    let mut tokens = text.split_whitespace();
+    let n: usize = tokens.next()?.parse().ok()?;
+    let _d = tokens.next()?;
+    let _q = tokens.next()?;
+    let weights: Vec<i64> = tokens
+        .take(n)
+        .map(|e| e.parse().ok())
+        .collect::<Option<_>>()?;
+    if weights.len() != n {
+        return None;
+    }
+    Some(weights)
+}
+
+impl Verifier {
+    pub fn from_env() -> Option<Verifier> {
+        read_true_weights().map(Verifier::new)
+    }
+
+    fn new(weights: Vec<i64>) -> Verifier {
+        Verifier {
+            weights,
+            checked_cached_results: FastHashSet::default(),
+            checked_edges: FastHashSet::default(),
+            check_count: 0,
+            first_error: None,
+        }
+    }
+
+    fn weight(&self, hash: u128) -> i64 {
+        iter_bits(hash).map(|i| self.weights[i]).sum()
+    }
+
+    fn group_weight(&self, group: &[usize]) -> i64 {
+        group.iter().map(|i| self.weights[*i]).sum()
+    }
+
+    ///
+    /// actionの後に呼ぶ
+    /// 1. 順位が確かとされている隣り合うグループの大小関係
+    /// 2. まだ確認していない比較結果のキャッシュ
+    /// 3. まだ確認していないグラフの辺（問い合わせで得た辺、推論した辺）
+    ///
+    pub fn verify(
+        &mut self,
+        groups: &[Vec<usize>],
+        rank: &dyn Ranking,
+        balancer: &Balancer,
+        action: &str,
+        query_count: usize,
+    ) {
+        if self.first_error.is_some() {
+            return;
+        }
+        self.check_count += 1;
+        if let Some(error) = self.find_error(groups, rank, balancer) {
+            let message = format!("after {} (query {}): {}", action, query_count, error);
+            eprintln!("verify_error: {}", message);
+            self.first_error = Some(message);
+        }
+    }
+
+    fn find_error(
+        &mut self,
+        groups: &[Vec<usize>],
+        rank: &dyn Ranking,
+        balancer: &Balancer,
+    ) -> Option<String> {
+        for i in 0..groups.len() - 1 {
+            if !rank.is_position_certain(i) || !rank.is_position_certain(i + 1) {
+                continue;
+            }
+            let (lighter, heavier) = (rank[i], rank[i + 1]);
+            let (lighter_w, heavier_w) = (
+                self.group_weight(&groups[lighter]),
+                self.group_weight(&groups[heavier]),
+            );
+            if lighter_w > heavier_w {
+                return Some(format!(
+                    "rank[{}] = {} ({}) is heavier than rank[{}] = {} ({})",
+                    i,
+                    lighter,
+                    lighter_w,
+                    i + 1,
+                    heavier,
+                    heavier_w
+                ));
+            }
+        }
+
+        for (&(left_hash, right_hash), result) in balancer.cached_results.iter() {
+            if !self.checked_cached_results.insert((left_hash, right_hash)) {
+                continue;
+            }
+            let (left_w, right_w) = (self.weight(left_hash), self.weight(right_hash));
+            let is_consistent = match result {
+                BalanceResult::Left => left_w <= right_w,
+                BalanceResult::Right => left_w >= right_w,
+                BalanceResult::Equal => left_w == right_w,
+                BalanceResult::Unknown => true,
+            };
+            if !is_consistent {
+                return Some(format!(
+                    "cached result {:?} for {:?} ({}) vs {:?} ({})",
+                    result,
+                    iter_bits(left_hash).collect::<Vec<_>>(),
+                    left_w,
+                    iter_bits(right_hash).collect::<Vec<_>>(),
+                    right_w
+                ));
+            }
+        }
+
+        for (&(lighter_hash, heavier_hash), kind) in balancer.edge_kinds.iter() {
+            if !self.checked_edges.insert((lighter_hash, heavier_hash)) {
+                continue;
+            }
+            let (lighter_w, heavier_w) = (self.weight(lighter_hash), self.weight(heavier_hash));
+            if lighter_w > heavier_w {
+                return Some(format!(
+                    "{:?} edge {:?} ({}) <= {:?} ({})",
+                    kind,
+                    iter_bits(lighter_hash).collect::<Vec<_>>(),
+                    lighter_w,
+                    iter_bits(heavier_hash).collect::<Vec<_>>(),
+                    heavier_w
+                ));
+            }
+        }
+        None
+    }
+
+    pub fn print(&self) {
+        eprintln!("verify_check_count:     {}", self.check_count);
+        eprintln!(
+            "verify_checked_results: {}",
+            self.checked_cached_results.len()
+        );
+        eprintln!("verify_checked_edges:   {}", self.checked_edges.len());
+        eprintln!(
+            "verify_first_error:     {}",
+            self.first_error.as_deref().unwrap_or("none")
+        );
+    }
+}